@@ -3,33 +3,59 @@ use super::{
     instruction::{Cond, Inst, Operand, Reg16, Reg8, Rotation, ShiftType},
     registers::Registers,
 };
+use crate::util::state::{Snapshot, StateReader, StateWriter};
 use crate::{
-    memory::{bus::Bus, dma::Dma, interrupts::Interrupt, ppu::Ppu},
+    memory::{
+        apu::Apu,
+        bus::Bus,
+        dma::{Dma, Hdma},
+        interrupts::Interrupt,
+        ppu::Ppu,
+    },
     util::helper::{combine_to_u16, split_u16, split_u32},
 };
 use std::io::Write;
 
+// The CPU is stopped for 2050 machine cycles during a speed switch
+const SPEED_SWITCH_MACHINE_CYCLES: u16 = 2050;
+
 pub struct Cpu {
-    pub(super) regs: Registers,
+    pub(crate) regs: Registers,
     pub bus: Bus,
 
     pub(crate) is_halted: bool,
     pub(crate) counter: u64, // count number of executed instructions
     pub(crate) cycles: u64,
+    is_second_half: bool, // in double speed two machine cycles take 4 dots
 }
 
 impl Cpu {
     pub fn new(bus: Bus) -> Cpu {
         Cpu {
-            regs: Registers::new(),
+            regs: Registers::new(bus.model, bus.cartridge.header_checksum(), bus.is_cgb_mode),
             bus,
 
             is_halted: false,
             counter: 0,
             cycles: 0,
+            is_second_half: false,
         }
     }
 
+    /// The registers the boot ROM of the bus' model leaves behind
+    pub fn reset_registers(&mut self) {
+        self.regs = Registers::new(
+            self.bus.model,
+            self.bus.cartridge.header_checksum(),
+            self.bus.is_cgb_mode,
+        );
+    }
+
+    /// Starts at 0x0000 with zeroed registers to run a boot ROM
+    pub fn power_on(&mut self) {
+        self.regs = Registers::default();
+    }
+
     pub fn fetch_and_execute(&mut self) {
         if self.is_halted {
             // eprintln!(
@@ -37,6 +63,10 @@ impl Cpu {
             //     self.counter, self.bus.timer, self.bus.int
             // );
             self.tick(1);
+        } else if self.bus.hdma.is_copying() {
+            // the CPU waits 8 microseconds for each block of VRAM DMA
+            Hdma::copy_block(&mut self.bus);
+            self.tick(if self.bus.is_double_speed { 16 } else { 8 });
         } else {
             let inst = self.fetch();
             self.execute(inst);
@@ -62,10 +92,26 @@ impl Cpu {
                 if self.bus.timer.tick() {
                     self.bus.int.request_interrupt(Interrupt::Timer);
                 }
-                self.cycles += 1;
+            }
+            if self.bus.serial.tick() {
+                self.bus.int.request_interrupt(Interrupt::Serial);
             }
             Dma::tick(&mut self.bus);
+
+            // The PPU and APU keep their rate in double speed,
+            // they advance by 4 dots every second machine cycle
+            if self.bus.is_double_speed {
+                self.cycles += 2;
+                self.is_second_half = !self.is_second_half;
+                if self.is_second_half {
+                    continue;
+                }
+            } else {
+                self.cycles += 4;
+            }
             Ppu::tick(&mut self.bus);
+            Apu::tick(&mut self.bus);
+            self.bus.cartridge.tick();
         }
     }
 
@@ -297,9 +343,19 @@ impl Cpu {
         self.is_halted = true;
     }
 
-    fn stop(&self) {
-        // TODO: What do we do here?
-        eprintln!("STOPPPPP");
+    fn stop(&mut self) {
+        if !(self.bus.is_cgb_mode && self.bus.is_speed_switch_armed) {
+            // TODO: Low power mode until a button is pressed
+            eprintln!("STOPPPPP");
+            return;
+        }
+        // CGB speed switch, DIV is reset and the CPU pauses while the clock settles
+        self.bus.is_double_speed = !self.bus.is_double_speed;
+        self.bus.is_speed_switch_armed = false;
+        self.bus.timer.reset_divider();
+        for _ in 0..SPEED_SWITCH_MACHINE_CYCLES {
+            self.tick(1);
+        }
     }
 
     fn disable_int(&mut self) {
@@ -647,7 +703,7 @@ impl Cpu {
 
     fn swap(&mut self, operand: Operand) {
         let data = self.get_8bit_operand(&operand);
-        let result = (data >> 4) | (data << 4);
+        let result = data.rotate_left(4);
         self.regs.set_flag_zero(result == 0);
         self.regs.set_flag_subtract(false);
         self.regs.set_flag_half_carry(false);
@@ -714,3 +770,23 @@ impl Cpu {
         self.regs.set_flag_carry(!self.regs.carry_flag())
     }
 }
+
+impl Snapshot for Cpu {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.regs.save_state(state);
+        state.bool(self.is_halted);
+        state.u64(self.counter);
+        state.u64(self.cycles);
+        state.bool(self.is_second_half);
+        self.bus.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.regs.load_state(state)?;
+        self.is_halted = state.bool()?;
+        self.counter = state.u64()?;
+        self.cycles = state.u64()?;
+        self.is_second_half = state.bool()?;
+        self.bus.load_state(state)
+    }
+}
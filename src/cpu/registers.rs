@@ -1,4 +1,6 @@
+use crate::model::Model;
 use crate::util::helper::{combine_to_u16, split_u16};
+use crate::util::state::{Snapshot, StateReader, StateWriter};
 
 pub enum Flag {
     Zero = 0b1000_0000,
@@ -26,16 +28,18 @@ pub struct Registers {
 }
 
 impl Registers {
-    pub fn new() -> Self {
+    /// The state the boot ROM of `model` leaves behind
+    pub fn new(model: Model, header_checksum: u8, is_cgb_mode: bool) -> Self {
+        let [a, f, b, c, d, e, h, l] = model.registers(header_checksum, is_cgb_mode);
         Registers {
-            a: 0x0001,
-            f: 0x00B0,
-            b: 0x0000,
-            c: 0x0013,
-            d: 0x0000,
-            e: 0x00D8,
-            h: 0x0001,
-            l: 0x004D,
+            a,
+            f,
+            b,
+            c,
+            d,
+            e,
+            h,
+            l,
             pc: 0x0100, // The entrypoint after the Nintendo internal ROM
             sp: 0xFFFE,
         }
@@ -138,13 +142,43 @@ impl Registers {
     }
 }
 
+impl Snapshot for Registers {
+    fn save_state(&self, state: &mut StateWriter) {
+        for register in [
+            self.a, self.f, self.b, self.c, self.d, self.e, self.h, self.l,
+        ] {
+            state.u8(register);
+        }
+        state.u16(self.sp);
+        state.u16(self.pc);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        for register in [
+            &mut self.a,
+            &mut self.f,
+            &mut self.b,
+            &mut self.c,
+            &mut self.d,
+            &mut self.e,
+            &mut self.h,
+            &mut self.l,
+        ] {
+            *register = state.u8()?;
+        }
+        self.sp = state.u16()?;
+        self.pc = state.u16()?;
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
     fn register_accessors() {
-        let mut regs = Registers::new();
+        let mut regs = Registers::new(Model::Dmg, 0, false);
 
         // AF
         regs.a = 0xEE;
@@ -0,0 +1,457 @@
+use crate::memory::ppu::{BG_MAP_WIDTH, TILE_DATA_WIDTH};
+use crate::util::resampler::Resampler;
+use crate::util::wav::WavWriter;
+use crate::{Button, Emulator, Link, Model, Rewind, RtcClock, Speed, APU_SAMPLE_RATE};
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::rect::Rect;
+use sdl2::render::{TextureCreator, WindowCanvas};
+use sdl2::video::WindowContext;
+use sdl2::Sdl;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+// 70224 cycles at 4.194304 MHz, the LCD runs at ~59.73 Hz and not 60 Hz
+const FRAME_DURATION: Duration = Duration::from_nanos(16_742_706);
+const AUDIO_SAMPLE_RATE: i32 = 48_000;
+const AUDIO_DEVICE_SAMPLES: u16 = 1024;
+// Emulation runs ahead until this much audio is queued
+const AUDIO_LATENCY: Duration = Duration::from_millis(50);
+// Save RAM is written back every few seconds so a crash loses little progress
+const FRAMES_BETWEEN_SAVES: u32 = 5 * 60;
+// Holding R rewinds up to a minute, the snapshots may use at most 256 MiB
+const REWIND_FRAMES: usize = 60 * 60;
+const REWIND_MEMORY: usize = 256 * 1024 * 1024;
+
+/// SDL window showing the LCD and optionally the background map and tile data
+struct Screen {
+    canvas: WindowCanvas,
+    texture_creator: TextureCreator<WindowContext>,
+}
+
+impl Screen {
+    // the debug views are above the screen
+    fn new(sdl_context: &Sdl, (width, height): (usize, usize)) -> Result<Screen, String> {
+        let video_subsystem = sdl_context.video()?;
+
+        let window = video_subsystem
+            .window("Game Boy", 256 + width.max(160) as u32, 256 + height as u32)
+            .position_centered()
+            .build()
+            .map_err(|e| e.to_string())?;
+
+        let canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
+        let texture_creator = canvas.texture_creator();
+
+        Ok(Screen {
+            canvas,
+            texture_creator,
+        })
+    }
+
+    fn copy_rgb(&mut self, pixels: &[u8], width: usize, height: usize, target: Rect) {
+        let mut texture = self
+            .texture_creator
+            .create_texture_streaming(PixelFormatEnum::RGB24, width as u32, height as u32)
+            .expect("Couldn't create texture!");
+        texture.update(None, pixels, width * 3).expect("");
+        self.canvas.copy(&texture, None, Some(target)).expect("");
+    }
+
+    fn present(&mut self, emulator: &Emulator, show_background: bool) {
+        self.canvas.clear();
+        if show_background {
+            self.copy_rgb(
+                emulator.bg_map(),
+                BG_MAP_WIDTH,
+                BG_MAP_WIDTH,
+                Rect::new(0, 0, 256, 256),
+            );
+            self.copy_rgb(
+                emulator.tile_data(),
+                TILE_DATA_WIDTH,
+                TILE_DATA_WIDTH,
+                Rect::new(256, 0, 20 * 8, 20 * 8),
+            );
+        }
+        let (width, height) = emulator.screen_size();
+        self.copy_rgb(
+            emulator.frame_buffer(),
+            width,
+            height,
+            Rect::new(0, 256, width as u32, height as u32),
+        );
+        self.canvas.present();
+    }
+}
+
+/// SDL audio queue fed with the resampled APU output.
+/// The queue level paces the emulation, so it runs at the speed the device plays.
+struct Audio {
+    queue: AudioQueue<f32>,
+    resampler: Resampler,
+    output_rate: u32,
+    target_queue_size: u32, // bytes
+}
+
+impl Audio {
+    fn new(sdl_context: &Sdl) -> Result<Audio, String> {
+        let audio_subsystem = sdl_context.audio()?;
+        let desired_spec = AudioSpecDesired {
+            freq: Some(AUDIO_SAMPLE_RATE),
+            channels: Some(2),
+            samples: Some(AUDIO_DEVICE_SAMPLES),
+        };
+        let queue: AudioQueue<f32> = audio_subsystem.open_queue(None, &desired_spec)?;
+        let spec = queue.spec();
+        if spec.channels != 2 {
+            return Err(format!("Unsupported channel count {}", spec.channels));
+        }
+        let bytes_per_second = spec.freq as u32 * 2 * std::mem::size_of::<f32>() as u32;
+        queue.resume();
+
+        Ok(Audio {
+            resampler: Resampler::new(APU_SAMPLE_RATE, spec.freq as u32),
+            output_rate: spec.freq as u32,
+            target_queue_size: (bytes_per_second as f64 * AUDIO_LATENCY.as_secs_f64()) as u32,
+            queue,
+        })
+    }
+
+    fn push(&mut self, samples: &[f32]) {
+        let samples = self.resampler.resample(samples);
+        if let Err(error) = self.queue.queue_audio(&samples) {
+            eprintln!("Could not queue audio: {}", error);
+        }
+    }
+
+    // At other speeds the samples are played faster or slower, shifting the pitch,
+    // so the queue still paces the emulation
+    fn set_speed(&mut self, speed: Speed) {
+        if speed != Speed::Uncapped {
+            let input_rate = APU_SAMPLE_RATE as f64 * speed.multiplier();
+            self.resampler = Resampler::new(input_rate as u32, self.output_rate);
+        }
+    }
+
+    fn is_ahead(&self) -> bool {
+        self.queue.size() > self.target_queue_size
+    }
+}
+
+/// Writes the mixed stereo output and optionally each channel to WAV files
+struct AudioRecorder {
+    mixed: WavWriter,
+    channels: Vec<WavWriter>, // empty unless each channel is recorded
+}
+
+impl AudioRecorder {
+    // out.wav gets the channels out.ch1.wav to out.ch4.wav next to it
+    fn new(path: &Path, record_channels: bool) -> Result<AudioRecorder, String> {
+        let mut channels = Vec::new();
+        if record_channels {
+            for channel in 1..=4 {
+                let channel_path = path.with_extension(format!("ch{}.wav", channel));
+                channels.push(WavWriter::create(&channel_path, 1, APU_SAMPLE_RATE)?);
+            }
+        }
+        Ok(AudioRecorder {
+            mixed: WavWriter::create(path, 2, APU_SAMPLE_RATE)?,
+            channels,
+        })
+    }
+
+    fn write(&mut self, samples: &[f32], channel_samples: [Vec<f32>; 4]) -> Result<(), String> {
+        self.mixed.write_samples(samples)?;
+        for (wav, samples) in self.channels.iter_mut().zip(channel_samples) {
+            wav.write_samples(&samples)?;
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<(), String> {
+        self.mixed.finish()?;
+        for wav in self.channels {
+            wav.finish()?;
+        }
+        Ok(())
+    }
+}
+
+fn button_for_key(key: Keycode) -> Option<Button> {
+    match key {
+        Keycode::Up => Some(Button::Up),
+        Keycode::Down => Some(Button::Down),
+        Keycode::Left => Some(Button::Left),
+        Keycode::Right => Some(Button::Right),
+        Keycode::X => Some(Button::A),
+        Keycode::Z => Some(Button::B),
+        Keycode::Backspace => Some(Button::Select),
+        Keycode::Return => Some(Button::Start),
+        _ => None,
+    }
+}
+
+// The number keys select one of ten save state slots
+fn slot_for_key(key: Keycode) -> Option<u8> {
+    let keys = [
+        Keycode::Num0,
+        Keycode::Num1,
+        Keycode::Num2,
+        Keycode::Num3,
+        Keycode::Num4,
+        Keycode::Num5,
+        Keycode::Num6,
+        Keycode::Num7,
+        Keycode::Num8,
+        Keycode::Num9,
+    ];
+    keys.iter()
+        .position(|slot_key| *slot_key == key)
+        .map(|slot| slot as u8)
+}
+
+// Save states are kept next to the ROM as game.ss0 to game.ss9
+fn state_path(rom_path: &Path, slot: u8) -> PathBuf {
+    rom_path.with_extension(format!("ss{}", slot))
+}
+
+fn save_state(emulator: &Emulator, path: &Path) -> Result<(), String> {
+    std::fs::write(path, emulator.save_state()).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+fn load_state(emulator: &mut Emulator, path: &Path) -> Result<(), String> {
+    let state = std::fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    emulator.load_state(&state)
+}
+
+/// Command line options of the SDL frontend
+pub struct Options {
+    pub debug_print: bool,
+    pub draw_background: bool,
+    pub rtc_clock: RtcClock,
+    pub record_audio: Option<PathBuf>,
+    pub record_channels: bool,
+    pub print_serial: bool,
+    pub link_listen: Option<String>,
+    pub link_connect: Option<String>,
+    pub printer_dir: Option<PathBuf>,
+    pub boot_rom: Option<PathBuf>,
+    pub model: Option<Model>, // detected from the cartridge if not set
+    pub rewind_interval: u32, // frames between rewind snapshots
+    pub speed: Speed,
+    pub rom_path: PathBuf,
+}
+
+pub fn start(options: Options) -> Result<(), String> {
+    let mut emulator = Emulator::from_file(&options.rom_path)?;
+    emulator.set_debug_print(options.debug_print);
+    emulator.set_rtc_clock(options.rtc_clock);
+    if let Some(model) = options.model {
+        emulator.set_model(model);
+    }
+    if let Some(path) = &options.boot_rom {
+        let boot_rom = std::fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        emulator.load_boot_rom(boot_rom)?;
+    }
+    if options.print_serial {
+        emulator.set_serial_sink(|byte| {
+            print!("{}", byte as char);
+            let _ = std::io::stdout().flush();
+        });
+    }
+    if let Some(address) = &options.link_listen {
+        emulator.connect_link(Link::listen(address)?);
+    } else if let Some(address) = &options.link_connect {
+        emulator.connect_link(Link::connect(address)?);
+    } else if let Some(directory) = &options.printer_dir {
+        emulator.connect_printer(directory.clone());
+    }
+    let mut show_background = options.draw_background;
+
+    let mut recorder = match &options.record_audio {
+        Some(path) => Some(AudioRecorder::new(path, options.record_channels)?),
+        None => None,
+    };
+    emulator.set_recording_channels(recorder.is_some() && options.record_channels);
+
+    let sdl_context = sdl2::init()?;
+    let mut screen = Screen::new(&sdl_context, emulator.screen_size())?;
+    let mut audio = match Audio::new(&sdl_context) {
+        Ok(audio) => Some(audio),
+        Err(error) => {
+            eprintln!("No audio, falling back to timed frames: {}", error);
+            None
+        }
+    };
+
+    let mut event_pump = sdl_context.event_pump()?;
+    let mut is_paused = false;
+    let mut frames_since_save = 0;
+    let mut state_slot = 0;
+    // the other side of a link cable can't be rewound
+    let is_linked = options.link_listen.is_some() || options.link_connect.is_some();
+    let mut rewind = (!is_linked).then(|| {
+        let capacity = REWIND_FRAMES.div_ceil(options.rewind_interval as usize);
+        Rewind::new(options.rewind_interval, capacity, REWIND_MEMORY)
+    });
+    let mut is_rewinding = false;
+    let mut speed = options.speed;
+    if let Some(audio) = &mut audio {
+        audio.set_speed(speed);
+    }
+
+    'main_loop: loop {
+        for event in event_pump.poll_iter() {
+            match event {
+                Event::Quit { .. } => break 'main_loop,
+                Event::KeyDown {
+                    keycode: Some(key), ..
+                } => match key {
+                    Keycode::Escape => break 'main_loop,
+                    Keycode::P => {
+                        is_paused = !is_paused;
+                        if is_paused {
+                            println!("Paused!")
+                        }
+                    }
+                    Keycode::D => show_background = !show_background,
+                    Keycode::Tab | Keycode::Equals | Keycode::Minus => {
+                        speed = match key {
+                            Keycode::Tab => speed.toggle_uncapped(),
+                            Keycode::Equals => speed.faster(),
+                            _ => speed.slower(),
+                        };
+                        if let Some(audio) = &mut audio {
+                            audio.set_speed(speed);
+                        }
+                        println!("Speed {}", speed);
+                    }
+                    Keycode::R => is_rewinding = rewind.is_some(),
+                    Keycode::F5 => {
+                        let path = state_path(&options.rom_path, state_slot);
+                        match save_state(&emulator, &path) {
+                            Ok(()) => println!("Saved state to slot {}", state_slot),
+                            Err(error) => eprintln!("Could not save state: {}", error),
+                        }
+                    }
+                    Keycode::F8 => {
+                        let path = state_path(&options.rom_path, state_slot);
+                        match load_state(&mut emulator, &path) {
+                            Ok(()) => println!("Loaded state from slot {}", state_slot),
+                            Err(error) => eprintln!("Could not load state: {}", error),
+                        }
+                    }
+                    _ => {
+                        if let Some(slot) = slot_for_key(key) {
+                            state_slot = slot;
+                            println!("Selected save state slot {}", state_slot);
+                        } else if let Some(button) = button_for_key(key) {
+                            emulator.set_button(button, true);
+                        }
+                    }
+                },
+                Event::KeyUp {
+                    keycode: Some(key), ..
+                } => {
+                    if key == Keycode::R {
+                        is_rewinding = false;
+                    }
+                    if let Some(button) = button_for_key(key) {
+                        emulator.set_button(button, false);
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        if is_paused {
+            sleep(Duration::from_millis(100));
+            continue;
+        }
+
+        let is_uncapped = speed == Speed::Uncapped;
+        if let Some(audio) = audio.as_ref().filter(|_| !is_uncapped) {
+            while audio.is_ahead() {
+                sleep(Duration::from_millis(1));
+            }
+        }
+
+        let before_run = Instant::now();
+        if is_rewinding {
+            // one snapshot per frame, stays on the oldest one once they run out
+            if let Some(state) = rewind.as_mut().and_then(|rewind| rewind.pop()) {
+                if let Err(error) = emulator.load_state(&state) {
+                    eprintln!("Could not rewind: {}", error);
+                }
+            }
+            screen.present(&emulator, show_background);
+            let delta_time = before_run.elapsed();
+            if delta_time < FRAME_DURATION {
+                sleep(FRAME_DURATION - delta_time);
+            }
+            continue;
+        }
+
+        // when running faster than the display only every few frames are rendered
+        let mut frames_run = 0;
+        loop {
+            emulator.step_frame();
+            if let Some(rewind) = &mut rewind {
+                rewind.push_frame(&emulator);
+            }
+            let samples = emulator.take_audio_samples();
+            if let Some(audio) = audio.as_mut().filter(|_| !is_uncapped) {
+                audio.push(&samples);
+            }
+            if let Some(audio_recorder) = &mut recorder {
+                if let Err(error) = audio_recorder.write(&samples, emulator.take_channel_samples())
+                {
+                    eprintln!("Stopped recording audio: {}", error);
+                    // the header sizes are only written on finish
+                    if let Some(Err(error)) = recorder.take().map(AudioRecorder::finish) {
+                        eprintln!("Could not finish recording audio: {}", error);
+                    }
+                }
+            }
+
+            frames_since_save += 1;
+            if frames_since_save == FRAMES_BETWEEN_SAVES {
+                frames_since_save = 0;
+                if let Err(error) = emulator.flush_save() {
+                    eprintln!("Could not write save file: {}", error);
+                }
+            }
+            frames_run += 1;
+            let is_done = match speed.frames_per_present() {
+                Some(frames) => frames_run >= frames,
+                None => before_run.elapsed() >= FRAME_DURATION,
+            };
+            if is_done {
+                break;
+            }
+        }
+
+        if show_background {
+            emulator.draw_debug_views();
+        }
+        screen.present(&emulator, show_background);
+
+        let frame_time = FRAME_DURATION.mul_f64(frames_run as f64 / speed.multiplier());
+        let delta_time = before_run.elapsed();
+        if audio.is_none() && delta_time < frame_time {
+            sleep(frame_time - delta_time);
+        }
+    }
+    // the save is written even if the recording can't be finished
+    if let Some(audio_recorder) = recorder {
+        if let Err(error) = audio_recorder.finish() {
+            eprintln!("Could not finish recording audio: {}", error);
+        }
+    }
+    emulator.save()
+}
@@ -1,4 +1,17 @@
-struct Input {
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Button {
+    Up,
+    Down,
+    Left,
+    Right,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+#[derive(Default, Debug)]
+pub struct Input {
     up: bool,
     down: bool,
     left: bool,
@@ -8,3 +21,35 @@ struct Input {
     select: bool,
     start: bool,
 }
+
+impl Input {
+    pub fn new() -> Self {
+        Input::default()
+    }
+
+    pub fn is_pressed(&self, button: Button) -> bool {
+        match button {
+            Button::Up => self.up,
+            Button::Down => self.down,
+            Button::Left => self.left,
+            Button::Right => self.right,
+            Button::A => self.a,
+            Button::B => self.b,
+            Button::Select => self.select,
+            Button::Start => self.start,
+        }
+    }
+
+    pub fn set_pressed(&mut self, button: Button, pressed: bool) {
+        match button {
+            Button::Up => self.up = pressed,
+            Button::Down => self.down = pressed,
+            Button::Left => self.left = pressed,
+            Button::Right => self.right = pressed,
+            Button::A => self.a = pressed,
+            Button::B => self.b = pressed,
+            Button::Select => self.select = pressed,
+            Button::Start => self.start = pressed,
+        }
+    }
+}
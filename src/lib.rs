@@ -1,84 +1,451 @@
 mod cpu;
+#[cfg(feature = "sdl")]
+mod frontend;
+mod input;
+mod link;
 mod memory;
+mod model;
+mod rewind;
+mod speed;
 mod util;
 
 use cpu::cpu_impl::Cpu;
-use memory::bus::Bus;
+use memory::bus::{Bus, BOOT_ROM_SIZE};
 use memory::cartridge::Cartridge;
+use memory::interrupts::Interrupt;
 use memory::ppu::Ppu;
-use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
+use memory::printer::Printer;
 use std::io;
-use std::path::PathBuf;
-use std::thread::sleep;
-use std::time::{Duration, Instant};
+use std::path::{Path, PathBuf};
+use util::state::{Snapshot, StateReader, StateWriter};
+
+#[cfg(feature = "sdl")]
+pub use frontend::{start, Options};
+pub use input::Button;
+pub use link::Link;
+pub use memory::apu::APU_SAMPLE_RATE;
+pub use memory::mbc::rtc::RtcClock;
+pub use memory::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+pub use memory::sgb::{SGB_SCREEN_HEIGHT, SGB_SCREEN_WIDTH};
+pub use model::Model;
+pub use rewind::Rewind;
+pub use speed::Speed;
 
 const CYCLES_IN_ONE_SIXTIETH_S: u64 = 70224;
-const ONE_SIXTIETH_S: Duration = Duration::from_nanos(16_700_000);
-
-pub fn start(debug_print: bool, draw_background: bool, rom_path: PathBuf) -> Result<(), String> {
-    let cartridge = Cartridge::load_from_file(rom_path.to_str().unwrap())?;
-    cartridge.print_info();
-    let mut show_background = draw_background;
-
-    let sdl_context = sdl2::init()?;
-    let ppu = Ppu::new(&sdl_context);
-
-    let bus = Bus::new(cartridge, ppu);
-    let mut cpu = Cpu::new(bus);
-
-    let mut event_pump = sdl_context.event_pump()?;
-    let mut is_paused = false;
-
-    'main_loop: loop {
-        for event in event_pump.poll_iter() {
-            match event {
-                Event::Quit { .. } => break 'main_loop,
-                Event::KeyDown {
-                    keycode: Some(key), ..
-                } => match key {
-                    Keycode::Escape => break 'main_loop,
-                    Keycode::P => {
-                        is_paused = !is_paused;
-                        if is_paused {
-                            println!("Paused!")
-                        }
-                    }
-                    Keycode::D => show_background = !show_background,
-                    _ => (),
-                },
-                _ => (),
-            }
+
+/// The emulated machine without any window, audio or input devices attached.
+/// A frontend drives it frame by frame and presents the frame buffer.
+pub struct Emulator {
+    cpu: Cpu,
+    debug_print: bool,
+    is_rumbling: bool,
+    rumble_hook: Option<Box<dyn FnMut(bool)>>,
+    link: Option<Link>,
+}
+
+impl Emulator {
+    pub fn from_file(rom_path: &Path) -> Result<Emulator, String> {
+        let path = rom_path
+            .to_str()
+            .ok_or_else(|| format!("Invalid rom path: {}", rom_path.display()))?;
+        let cartridge = Cartridge::load_from_file(path)?;
+        cartridge.print_info();
+        Ok(Self::new(cartridge))
+    }
+
+    pub fn from_bytes(rom: Vec<u8>) -> Result<Emulator, String> {
+        Ok(Self::new(Cartridge::from_bytes(rom)?))
+    }
+
+    fn new(cartridge: Cartridge) -> Emulator {
+        let mut emulator = Emulator {
+            cpu: Cpu::new(Bus::new(cartridge)),
+            debug_print: false,
+            is_rumbling: false,
+            rumble_hook: None,
+            link: None,
+        };
+        // color cartridges start in CGB mode
+        let model = if emulator.cpu.bus.cartridge.supports_cgb() {
+            Model::Cgb
+        } else {
+            Model::Dmg
+        };
+        emulator.set_model(model);
+        emulator
+    }
+
+    /// Starts in the post-boot state of `model`, call before running
+    pub fn set_model(&mut self, model: Model) {
+        self.cpu.bus.set_model(model);
+        self.cpu.reset_registers();
+    }
+
+    /// Runs the 256 byte DMG boot ROM from power-on instead of starting
+    /// with the post-boot state at the cartridge entry point
+    pub fn load_boot_rom(&mut self, boot_rom: Vec<u8>) -> Result<(), String> {
+        if boot_rom.len() != BOOT_ROM_SIZE {
+            return Err(format!(
+                "Boot ROM has to be {} bytes, not {}",
+                BOOT_ROM_SIZE,
+                boot_rom.len()
+            ));
         }
+        self.cpu.power_on();
+        self.cpu.bus.map_boot_rom(boot_rom);
+        Ok(())
+    }
+
+    /// Dump the cpu state to stdout before each instruction
+    pub fn set_debug_print(&mut self, debug_print: bool) {
+        self.debug_print = debug_print;
+    }
+
+    /// Selects what drives the real time clock of MBC3 cartridges
+    pub fn set_rtc_clock(&mut self, clock: RtcClock) {
+        self.cpu.bus.cartridge.set_rtc_clock(clock);
+    }
+
+    /// Writes battery-backed cartridge RAM to the save file
+    pub fn save(&mut self) -> Result<(), String> {
+        self.cpu.bus.cartridge.save()
+    }
+
+    /// Writes battery-backed cartridge RAM to the save file if it changed
+    pub fn flush_save(&mut self) -> Result<(), String> {
+        self.cpu.bus.cartridge.flush_save()
+    }
+
+    /// Serializes the whole machine, devices attached by the frontend are not included
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        self.cpu.save_state(&mut state);
+        state.into_bytes()
+    }
 
-        if is_paused {
-            sleep(Duration::from_millis(100));
-            continue;
+    /// Restores a state from `save_state`, on error the machine is left as it was
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+        let mut state = StateReader::new(data)?;
+        let backup = self.save_state();
+        let result = self.cpu.load_state(&mut state).and_then(|()| {
+            if state.is_at_end() {
+                Ok(())
+            } else {
+                Err("The save state has unexpected trailing data".to_string())
+            }
+        });
+        if result.is_err() {
+            let mut backup = StateReader::new(&backup)?;
+            self.cpu
+                .load_state(&mut backup)
+                .expect("Could not restore the machine state");
         }
+        result
+    }
 
-        let before_run = Instant::now();
-        while cpu.cycles < CYCLES_IN_ONE_SIXTIETH_S {
-            if debug_print {
-                cpu.debug_print(&mut io::stdout());
+    /// Runs the machine for the duration of one frame
+    pub fn step_frame(&mut self) {
+        while self.cpu.cycles < CYCLES_IN_ONE_SIXTIETH_S {
+            if self.debug_print {
+                self.cpu.debug_print(&mut io::stdout());
             }
-            cpu.fetch_and_execute();
+            let cycles_before = self.cpu.cycles;
+            self.cpu.fetch_and_execute();
+            self.check_rumble();
+            self.sync_link(self.cpu.cycles - cycles_before);
         }
+        self.cpu.cycles -= CYCLES_IN_ONE_SIXTIETH_S;
+    }
 
-        if show_background {
-            let now = Instant::now();
+    /// Calls `hook` whenever the rumble motor of the cartridge is turned on or off
+    pub fn set_rumble_hook(&mut self, hook: impl FnMut(bool) + 'static) {
+        self.rumble_hook = Some(Box::new(hook));
+    }
 
-            Ppu::draw(&mut cpu.bus);
+    pub fn is_rumbling(&self) -> bool {
+        self.is_rumbling
+    }
 
-            // eprintln!("Drawing took: {:.2?}", now.elapsed());
+    fn check_rumble(&mut self) {
+        let is_rumbling = self.cpu.bus.cartridge.mbc.is_rumbling();
+        if is_rumbling == self.is_rumbling {
+            return;
+        }
+        self.is_rumbling = is_rumbling;
+        if let Some(hook) = &mut self.rumble_hook {
+            hook(is_rumbling);
         }
+    }
+
+    /// Calls `sink` with every byte the game sends over the link port,
+    /// e.g. the results printed by test ROMs
+    pub fn set_serial_sink(&mut self, mut sink: impl FnMut(u8) + 'static) {
+        self.cpu.bus.serial.connect(Box::new(move |byte| {
+            sink(byte);
+            0xFF
+        }));
+    }
+
+    /// Connects the link port to another emulator
+    pub fn connect_link(&mut self, link: Link) {
+        self.cpu.bus.serial.connect_link();
+        self.link = Some(link);
+    }
+
+    /// Attaches a Game Boy Printer to the link port, prints are written to `output_dir`
+    pub fn connect_printer(&mut self, output_dir: PathBuf) {
+        self.cpu
+            .bus
+            .serial
+            .connect_printer(Printer::new(output_dir));
+        self.link = None;
+    }
+
+    fn sync_link(&mut self, cycles: u64) {
+        let Some(link) = &mut self.link else {
+            return;
+        };
+        if let Err(error) = link.advance(cycles, &mut self.cpu.bus) {
+            eprintln!("Link cable disconnected: {}", error);
+            self.cpu.bus.serial.disconnect();
+            self.link = None;
+        }
+    }
+
+    /// The 160x144 LCD image as RGB24, or the 256x224 picture with the border in SGB mode
+    pub fn frame_buffer(&self) -> &[u8] {
+        if self.cpu.bus.is_sgb_mode {
+            self.cpu.bus.sgb.screen()
+        } else {
+            self.cpu.bus.ppu.frame_buffer()
+        }
+    }
+
+    /// Width and height of the frame buffer
+    pub fn screen_size(&self) -> (usize, usize) {
+        if self.cpu.bus.is_sgb_mode {
+            (SGB_SCREEN_WIDTH, SGB_SCREEN_HEIGHT)
+        } else {
+            (SCREEN_WIDTH, SCREEN_HEIGHT)
+        }
+    }
+
+    /// Renders the tile data and background map debug views
+    pub fn draw_debug_views(&mut self) {
+        Ppu::draw(&mut self.cpu.bus);
+    }
+
+    /// All 384 tiles in VRAM as a 160x160 RGB24 image
+    pub fn tile_data(&self) -> &[u8] {
+        self.cpu.bus.ppu.tile_data()
+    }
+
+    /// The whole 256x256 background map as RGB24
+    pub fn bg_map(&self) -> &[u8] {
+        self.cpu.bus.ppu.bg_map()
+    }
+
+    /// Interleaved stereo samples at APU_SAMPLE_RATE generated since the last call
+    pub fn take_audio_samples(&mut self) -> Vec<f32> {
+        self.cpu.bus.apu.take_samples()
+    }
+
+    /// Also collect the output of each channel for `take_channel_samples`
+    pub fn set_recording_channels(&mut self, is_recording_channels: bool) {
+        self.cpu
+            .bus
+            .apu
+            .set_recording_channels(is_recording_channels);
+    }
 
-        let delta_time = before_run.elapsed();
-        if delta_time < ONE_SIXTIETH_S {
-            let time_to_sleep = ONE_SIXTIETH_S - delta_time;
-            sleep(time_to_sleep);
-            // eprintln!("Done frame, slept: {} ms", time_to_sleep.as_millis());
+    /// Mono samples of the four APU channels generated since the last call
+    pub fn take_channel_samples(&mut self) -> [Vec<f32>; 4] {
+        self.cpu.bus.apu.take_channel_samples()
+    }
+
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        let bus = &mut self.cpu.bus;
+        if bus.joypad.set_pressed(button, pressed) {
+            bus.int.request_interrupt(Interrupt::Joypad);
         }
-        cpu.cycles = 0;
     }
-    Ok(())
+
+    pub fn is_button_pressed(&self, button: Button) -> bool {
+        self.cpu.bus.joypad.is_pressed(button)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blank_rom() -> Vec<u8> {
+        // A 32 KiB ROM-only cartridge that loops forever at 0x0100
+        let mut rom = vec![0; 0x8000];
+        rom[0x0100] = 0x18; // JR -2
+        rom[0x0101] = 0xFE;
+        rom
+    }
+
+    #[test]
+    fn step_frame_without_display() {
+        let mut emulator = Emulator::from_bytes(blank_rom()).unwrap();
+        emulator.step_frame();
+        assert_eq!(
+            emulator.frame_buffer().len(),
+            SCREEN_WIDTH * SCREEN_HEIGHT * 3
+        );
+        assert!(emulator.cpu.cycles < CYCLES_IN_ONE_SIXTIETH_S);
+    }
+
+    #[test]
+    fn one_frame_of_audio_samples() {
+        let mut emulator = Emulator::from_bytes(blank_rom()).unwrap();
+        emulator.step_frame();
+        let samples = emulator.take_audio_samples();
+        let expected = 2 * CYCLES_IN_ONE_SIXTIETH_S as usize / 4 / 16;
+        assert!(samples.len().abs_diff(expected) <= 2);
+        assert!(emulator.take_audio_samples().is_empty());
+    }
+
+    #[test]
+    fn rumble_hook() {
+        let mut rom = blank_rom();
+        // MBC5+RUMBLE
+        rom[0x0147] = 0x1C;
+        // LD A, 0x08; LD (0x4000), A; XOR A; LD (0x4000), A; JR -2
+        rom[0x0100..0x010B].copy_from_slice(&[
+            0x3E, 0x08, 0xEA, 0x00, 0x40, 0xAF, 0xEA, 0x00, 0x40, 0x18, 0xFE,
+        ]);
+        let mut emulator = Emulator::from_bytes(rom).unwrap();
+
+        let changes = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let hook_changes = changes.clone();
+        emulator.set_rumble_hook(move |is_rumbling| hook_changes.borrow_mut().push(is_rumbling));
+        emulator.step_frame();
+        assert_eq!(*changes.borrow(), vec![true, false]);
+    }
+
+    #[test]
+    fn serial_sink_collects_output() {
+        let mut rom = blank_rom();
+        // LD A, 'H'; LDH (0x01), A; LD A, 0x81; LDH (0x02), A; JR -2
+        rom[0x0100..0x010A]
+            .copy_from_slice(&[0x3E, b'H', 0xE0, 0x01, 0x3E, 0x81, 0xE0, 0x02, 0x18, 0xFE]);
+        let mut emulator = Emulator::from_bytes(rom).unwrap();
+
+        let output = std::rc::Rc::new(std::cell::RefCell::new(String::new()));
+        let sink_output = output.clone();
+        emulator.set_serial_sink(move |byte| sink_output.borrow_mut().push(byte as char));
+        emulator.step_frame();
+        assert_eq!(*output.borrow(), "H");
+        assert!(emulator.cpu.bus.int.requested() & Interrupt::Serial.bit() != 0);
+    }
+
+    #[test]
+    fn boot_rom_is_unmapped_by_ff50() {
+        let mut boot_rom = vec![0; BOOT_ROM_SIZE];
+        // LD A, 0x42; LD (0xC000), A; LD A, 1; LDH (0x50), A
+        boot_rom[..9].copy_from_slice(&[0x3E, 0x42, 0xEA, 0x00, 0xC0, 0x3E, 0x01, 0xE0, 0x50]);
+        let mut rom = blank_rom();
+        rom[0] = 0x76;
+        let mut emulator = Emulator::from_bytes(rom).unwrap();
+        assert!(emulator.load_boot_rom(vec![0; 10]).is_err());
+        emulator.load_boot_rom(boot_rom).unwrap();
+        assert_eq!(emulator.cpu.regs.pc, 0);
+        assert_eq!(emulator.cpu.bus.read(0x0000), 0x3E);
+
+        emulator.step_frame();
+        assert_eq!(emulator.cpu.bus.read(0xC000), 0x42);
+        assert_eq!(emulator.cpu.bus.read(0x0000), 0x76);
+        // the NOPs after the boot ROM lead to the JR -2 at the entry point
+        assert!((0x100..0x102).contains(&emulator.cpu.regs.pc));
+    }
+
+    #[test]
+    fn post_boot_state_per_model() {
+        let mut emulator = Emulator::from_bytes(blank_rom()).unwrap();
+        emulator.set_model(Model::Mgb);
+        assert_eq!(emulator.cpu.regs.a, 0xFF);
+        assert_eq!(emulator.cpu.bus.read(0xFF04), 0xAB);
+        assert_eq!(emulator.cpu.bus.read(0xFF40), 0x91);
+        assert_eq!(emulator.cpu.bus.read(0xFF07), 0xF8);
+
+        emulator.set_model(Model::Cgb);
+        assert_eq!(emulator.cpu.regs.a, 0x11);
+        assert_eq!(emulator.cpu.regs.pc, 0x0100);
+        assert_eq!(emulator.cpu.bus.read(0xFF04), 0x26);
+    }
+
+    #[test]
+    fn color_cartridge_starts_in_cgb_mode() {
+        let mut rom = blank_rom();
+        rom[0x0143] = 0xC0;
+        let mut emulator = Emulator::from_bytes(rom).unwrap();
+        assert!(emulator.cpu.bus.is_cgb_mode);
+        assert_eq!(emulator.cpu.regs.a, 0x11);
+
+        // WRAM bank 0 selects bank 1
+        let bus = &mut emulator.cpu.bus;
+        bus.write(0xFF70, 2);
+        bus.write(0xD000, 0x22);
+        bus.write(0xFF70, 0);
+        assert_eq!(bus.read(0xFF70), 0xF9);
+        assert_eq!(bus.read(0xD000), 0x00);
+        bus.write(0xFF70, 2);
+        assert_eq!(bus.read(0xD000), 0x22);
+
+        emulator.set_model(Model::Dmg);
+        assert!(!emulator.cpu.bus.is_cgb_mode);
+        assert_eq!(emulator.cpu.bus.read(0xFF70), 0xFF);
+    }
+
+    #[test]
+    fn stop_switches_to_double_speed() {
+        // LD A, 1; LDH (0x4D), A; STOP; JR -2
+        let mut rom = blank_rom();
+        rom[0x0143] = 0x80;
+        rom[0x0100..0x0108].copy_from_slice(&[0x3E, 0x01, 0xE0, 0x4D, 0x10, 0x00, 0x18, 0xFE]);
+        let mut emulator = Emulator::from_bytes(rom).unwrap();
+        assert_eq!(emulator.cpu.bus.read(0xFF4D), 0x7E);
+
+        emulator.step_frame();
+        let bus = &mut emulator.cpu.bus;
+        assert!(bus.is_double_speed);
+        assert_eq!(bus.read(0xFF4D), 0xFE);
+
+        // the DMG ignores KEY1
+        emulator.set_model(Model::Dmg);
+        emulator.cpu.bus.write(0xFF4D, 1);
+        assert_eq!(emulator.cpu.bus.read(0xFF4D), 0xFF);
+    }
+
+    #[test]
+    fn save_state_round_trip() {
+        // INC A; JR -3
+        let mut rom = blank_rom();
+        rom[0x0100..0x0103].copy_from_slice(&[0x3C, 0x18, 0xFD]);
+        let mut emulator = Emulator::from_bytes(rom.clone()).unwrap();
+        emulator.step_frame();
+        let state = emulator.save_state();
+
+        emulator.step_frame();
+        let after_two_frames = emulator.save_state();
+        emulator.load_state(&state).unwrap();
+        assert_eq!(emulator.save_state(), state);
+        emulator.step_frame();
+        assert_eq!(emulator.save_state(), after_two_frames);
+
+        // another cartridge and truncated states leave the machine untouched
+        rom[0x014D] = 0x12;
+        let mut other = Emulator::from_bytes(rom).unwrap();
+        assert!(other.load_state(&state).is_err());
+        assert!(emulator.load_state(&state[..state.len() / 2]).is_err());
+        assert_eq!(emulator.save_state(), after_two_frames);
+    }
+
+    #[test]
+    fn rom_without_header_is_rejected() {
+        assert!(Emulator::from_bytes(vec![0; 0x100]).is_err());
+    }
 }
@@ -0,0 +1,225 @@
+use crate::memory::bus::Bus;
+use crate::memory::interrupts::Interrupt;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+// Both sides sync every 4096 cycles, the time of one byte at 8192 Hz
+const SYNC_CYCLES: u64 = 4096;
+// Addresses with this prefix are Unix socket paths
+const UNIX_PREFIX: &str = "unix:";
+
+enum Transport {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    Channel(Sender<Vec<u8>>, Receiver<Vec<u8>>), // two cores in one process
+}
+
+/// A link cable to another emulator.
+/// Both sides run in lockstep: after every `SYNC_CYCLES` each side sends
+/// its serial state and the bytes it clocked out, then waits for the other side.
+pub struct Link {
+    transport: Transport,
+    cycles: u64, // cycles since the last sync
+}
+
+// A socket left behind by an earlier run is removed, anything else at the path is kept
+#[cfg(unix)]
+fn remove_stale_socket(path: &str) -> Result<(), String> {
+    use std::os::unix::fs::FileTypeExt;
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            std::fs::remove_file(path).map_err(|e| format!("{}: {}", path, e))
+        }
+        Ok(_) => Err(format!("{}: address in use", path)),
+        Err(_) => Ok(()),
+    }
+}
+
+impl Link {
+    fn new(transport: Transport) -> Link {
+        Link {
+            transport,
+            cycles: 0,
+        }
+    }
+
+    pub fn tcp(stream: TcpStream) -> Result<Link, String> {
+        stream.set_nodelay(true).map_err(|e| e.to_string())?;
+        Ok(Self::new(Transport::Tcp(stream)))
+    }
+
+    /// Waits for the other side to connect, `address` is `host:port` or `unix:path`
+    pub fn listen(address: &str) -> Result<Link, String> {
+        eprintln!("Waiting for link cable connection on {}", address);
+        #[cfg(unix)]
+        if let Some(path) = address.strip_prefix(UNIX_PREFIX) {
+            remove_stale_socket(path)?;
+            let listener = UnixListener::bind(path).map_err(|e| format!("{}: {}", path, e))?;
+            let (stream, _) = listener.accept().map_err(|e| e.to_string())?;
+            return Ok(Self::new(Transport::Unix(stream)));
+        }
+        let listener = TcpListener::bind(address).map_err(|e| format!("{}: {}", address, e))?;
+        let (stream, _) = listener.accept().map_err(|e| e.to_string())?;
+        Self::tcp(stream)
+    }
+
+    /// Connects to a listening side, `address` is `host:port` or `unix:path`
+    pub fn connect(address: &str) -> Result<Link, String> {
+        #[cfg(unix)]
+        if let Some(path) = address.strip_prefix(UNIX_PREFIX) {
+            let stream = UnixStream::connect(path).map_err(|e| format!("{}: {}", path, e))?;
+            return Ok(Self::new(Transport::Unix(stream)));
+        }
+        let stream = TcpStream::connect(address).map_err(|e| format!("{}: {}", address, e))?;
+        Self::tcp(stream)
+    }
+
+    /// Two connected ends for emulators running on different threads
+    pub fn pair() -> (Link, Link) {
+        let (first_sender, first_receiver) = channel();
+        let (second_sender, second_receiver) = channel();
+        (
+            Self::new(Transport::Channel(first_sender, second_receiver)),
+            Self::new(Transport::Channel(second_sender, first_receiver)),
+        )
+    }
+
+    /// Syncs with the other side once enough cycles have passed
+    pub fn advance(&mut self, cycles: u64, bus: &mut Bus) -> Result<(), String> {
+        self.cycles += cycles;
+        while self.cycles >= SYNC_CYCLES {
+            self.cycles -= SYNC_CYCLES;
+            self.sync(bus)?;
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self, bus: &mut Bus) -> Result<(), String> {
+        let serial = &mut bus.serial;
+        let data = serial.data();
+        let is_waiting = serial.is_waiting_for_clock();
+        let Some(peer) = serial.link_peer() else {
+            return Ok(());
+        };
+        let sent = std::mem::take(&mut peer.sent);
+
+        // message: waiting flag, SB, number of clocked out bytes, the bytes
+        let mut message = vec![is_waiting as u8, data, sent.len() as u8];
+        message.extend(sent);
+        self.send(message)?;
+        let received = self.receive()?;
+
+        let peer = serial.link_peer().unwrap();
+        peer.is_waiting = received[0] != 0;
+        peer.data = received[1];
+        for byte in &received[3..] {
+            if serial.clock_external(*byte) {
+                bus.int.request_interrupt(Interrupt::Serial);
+            }
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, message: Vec<u8>) -> Result<(), String> {
+        let result = match &mut self.transport {
+            Transport::Tcp(stream) => stream.write_all(&message),
+            #[cfg(unix)]
+            Transport::Unix(stream) => stream.write_all(&message),
+            Transport::Channel(sender, _) => {
+                return sender.send(message).map_err(|e| e.to_string());
+            }
+        };
+        result.map_err(|e| e.to_string())
+    }
+
+    fn receive(&mut self) -> Result<Vec<u8>, String> {
+        let stream: &mut dyn Read = match &mut self.transport {
+            Transport::Tcp(stream) => stream,
+            #[cfg(unix)]
+            Transport::Unix(stream) => stream,
+            Transport::Channel(_, receiver) => {
+                return receiver.recv().map_err(|e| e.to_string());
+            }
+        };
+        let mut message = vec![0; 3];
+        stream.read_exact(&mut message).map_err(|e| e.to_string())?;
+        let mut sent = vec![0; message[2] as usize];
+        stream.read_exact(&mut sent).map_err(|e| e.to_string())?;
+        message.extend(sent);
+        Ok(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Emulator;
+    use std::thread;
+
+    // LD A, data; LDH (0x01), A; LD A, control; LDH (0x02), A; JR -2
+    fn transfer_rom(data: u8, control: u8) -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0100..0x010A].copy_from_slice(&[
+            0x3E, data, 0xE0, 0x01, 0x3E, control, 0xE0, 0x02, 0x18, 0xFE,
+        ]);
+        rom
+    }
+
+    // Returns SB and SC after a few frames
+    fn run(rom: Vec<u8>, link: Link) -> (u8, u8) {
+        let mut emulator = Emulator::from_bytes(rom).unwrap();
+        emulator.connect_link(link);
+        for _ in 0..3 {
+            emulator.step_frame();
+        }
+        let bus = &emulator.cpu.bus;
+        (bus.serial.data(), bus.serial.control())
+    }
+
+    fn exchange(master: Link, slave: Link) {
+        let slave = thread::spawn(move || run(transfer_rom(0x22, 0x80), slave));
+        let (master_data, master_control) = run(transfer_rom(0x11, 0x81), master);
+        let (slave_data, slave_control) = slave.join().unwrap();
+
+        assert_eq!(master_data, 0x22);
+        assert_eq!(slave_data, 0x11);
+        assert_eq!(master_control & 0x80, 0);
+        assert_eq!(slave_control & 0x80, 0);
+    }
+
+    #[test]
+    fn exchange_in_one_process() {
+        let (master, slave) = Link::pair();
+        exchange(master, slave);
+    }
+
+    #[test]
+    fn exchange_over_tcp_loopback() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let slave = thread::spawn(move || Link::connect(&address).unwrap());
+        let (stream, _) = listener.accept().unwrap();
+        exchange(Link::tcp(stream).unwrap(), slave.join().unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn only_stale_sockets_are_removed() {
+        let path = std::env::temp_dir().join(format!("gb-link-test-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        assert!(remove_stale_socket(path).is_ok());
+
+        std::fs::write(path, b"save").unwrap();
+        assert!(remove_stale_socket(path).is_err());
+        assert_eq!(std::fs::read(path).unwrap(), b"save");
+        std::fs::remove_file(path).unwrap();
+
+        drop(UnixListener::bind(path).unwrap());
+        remove_stale_socket(path).unwrap();
+        assert!(std::fs::symlink_metadata(path).is_err());
+    }
+}
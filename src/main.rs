@@ -1,5 +1,5 @@
-use clap::{command, Parser};
-use gameboy_emulator::start;
+use clap::Parser;
+use gameboy_emulator::{start, Model, Options, RtcClock, Speed};
 use std::path::PathBuf;
 
 #[derive(Parser)]
@@ -13,11 +13,80 @@ struct Args {
     #[arg(short = 'b', long = "draw-bg", default_value_t = true)]
     draw_background: bool,
 
+    /// Drive the cartridge's real time clock by emulated cycles instead of the host's clock
+    #[arg(long = "emulated-rtc")]
+    emulated_rtc: bool,
+
+    /// Write the audio output to a 16-bit PCM WAV file
+    #[arg(long = "record-audio", value_name = "WAV")]
+    record_audio: Option<PathBuf>,
+
+    /// Also record each channel to <WAV>.ch1.wav to <WAV>.ch4.wav
+    #[arg(long = "record-channels", requires = "record_audio")]
+    record_channels: bool,
+
+    /// Print the bytes sent over the link port, e.g. the results of test ROMs
+    #[arg(long = "print-serial")]
+    print_serial: bool,
+
+    /// Wait for another emulator to connect a link cable (host:port or unix:path)
+    #[arg(long = "link-listen", value_name = "ADDRESS")]
+    link_listen: Option<String>,
+
+    /// Connect a link cable to a listening emulator (host:port or unix:path)
+    #[arg(
+        long = "link-connect",
+        value_name = "ADDRESS",
+        conflicts_with = "link_listen"
+    )]
+    link_connect: Option<String>,
+
+    /// Attach a Game Boy Printer that writes its prints as PNG files to this directory
+    #[arg(long = "printer", value_name = "DIR", conflicts_with_all = ["link_listen", "link_connect"])]
+    printer_dir: Option<PathBuf>,
+
+    /// Run this 256 byte DMG boot ROM before the cartridge
+    #[arg(long = "boot-rom", value_name = "PATH")]
+    boot_rom: Option<PathBuf>,
+
+    /// The hardware to emulate: dmg0, dmg, mgb, sgb, sgb2, cgb or agb.
+    /// Defaults to cgb for color cartridges and dmg otherwise
+    #[arg(long = "model")]
+    model: Option<Model>,
+
+    /// Frames between the snapshots kept for rewinding with R
+    #[arg(long = "rewind-interval", value_name = "FRAMES", default_value_t = 2,
+        value_parser = clap::value_parser!(u32).range(1..))]
+    rewind_interval: u32,
+
+    /// Emulation speed: 0.25, 0.5, 1, 2, 4 or uncapped. Change it with - and =, Tab toggles uncapped
+    #[arg(long = "speed", default_value = "1")]
+    speed: Speed,
+
     /// The path to the rom
     rom_path: PathBuf,
 }
 
 fn main() -> Result<(), String> {
     let args = Args::parse();
-    start(args.debug_print, args.draw_background, args.rom_path)
+    start(Options {
+        debug_print: args.debug_print,
+        draw_background: args.draw_background,
+        rtc_clock: if args.emulated_rtc {
+            RtcClock::Emulated
+        } else {
+            RtcClock::WallTime
+        },
+        record_audio: args.record_audio,
+        record_channels: args.record_channels,
+        print_serial: args.print_serial,
+        link_listen: args.link_listen,
+        link_connect: args.link_connect,
+        printer_dir: args.printer_dir,
+        boot_rom: args.boot_rom,
+        model: args.model,
+        rewind_interval: args.rewind_interval,
+        speed: args.speed,
+        rom_path: args.rom_path,
+    })
 }
@@ -0,0 +1,146 @@
+use crate::util::state::{Snapshot, StateReader, StateWriter};
+
+/// Disables the channel once it has played for the set length
+#[derive(Debug)]
+pub struct LengthCounter {
+    pub is_enabled: bool,
+    counter: u16,
+    max: u16, // 64 for the square and noise channels, 256 for the wave channel
+}
+
+impl LengthCounter {
+    pub fn new(max: u16) -> Self {
+        LengthCounter {
+            is_enabled: false,
+            counter: 0,
+            max,
+        }
+    }
+
+    /// NRx1 holds the length as `max - counter`
+    pub fn load(&mut self, length: u8) {
+        self.counter = self.max - length as u16;
+    }
+
+    pub fn trigger(&mut self) {
+        if self.counter == 0 {
+            self.counter = self.max;
+        }
+    }
+
+    /// Clocked at 256 Hz, returns whether the channel has to be disabled
+    pub fn clock(&mut self) -> bool {
+        if !self.is_enabled || self.counter == 0 {
+            return false;
+        }
+        self.counter -= 1;
+        self.counter == 0
+    }
+}
+
+/// Periodically raises or lowers the volume (NRx2)
+#[derive(Debug)]
+pub struct Envelope {
+    register: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    pub fn new() -> Self {
+        Envelope {
+            register: 0,
+            volume: 0,
+            timer: 0,
+        }
+    }
+
+    pub fn register(&self) -> u8 {
+        self.register
+    }
+
+    pub fn set_register(&mut self, data: u8) {
+        self.register = data;
+    }
+
+    fn initial_volume(&self) -> u8 {
+        self.register >> 4
+    }
+
+    fn is_increasing(&self) -> bool {
+        self.register & 0b1000 != 0
+    }
+
+    fn period(&self) -> u8 {
+        self.register & 0b111
+    }
+
+    /// The DAC is off if both the initial volume and direction bit are 0
+    pub fn is_dac_enabled(&self) -> bool {
+        self.register & 0xF8 != 0
+    }
+
+    pub fn volume(&self) -> u8 {
+        self.volume
+    }
+
+    pub fn trigger(&mut self) {
+        self.volume = self.initial_volume();
+        self.timer = self.period();
+    }
+
+    /// Clocked at 64 Hz
+    pub fn clock(&mut self) {
+        if self.period() == 0 {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = self.period();
+            if self.is_increasing() && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.is_increasing() && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+}
+
+/// Converts a digital 0-15 channel output to an analog -1.0..1.0 value
+pub fn dac(is_dac_enabled: bool, output: u8) -> f32 {
+    if is_dac_enabled {
+        output as f32 / 7.5 - 1.0
+    } else {
+        0.0
+    }
+}
+
+impl Snapshot for LengthCounter {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.is_enabled);
+        state.u16(self.counter);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.is_enabled = state.bool()?;
+        self.counter = state.u16()?.min(self.max);
+        Ok(())
+    }
+}
+
+impl Snapshot for Envelope {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.register);
+        state.u8(self.volume);
+        state.u8(self.timer);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.register = state.u8()?;
+        self.volume = state.u8()? & 0x0F;
+        self.timer = state.u8()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,400 @@
+mod channel;
+mod noise;
+mod square;
+mod wave;
+
+use self::channel::dac;
+use self::noise::NoiseChannel;
+use self::square::SquareChannel;
+use self::wave::WaveChannel;
+use crate::memory::bus::Bus;
+use crate::util::state::{Snapshot, StateReader, StateWriter};
+
+// Samples are averaged over 16 machine cycles (1 MiHz / 16)
+const CYCLES_PER_SAMPLE: u32 = 16;
+pub const APU_SAMPLE_RATE: u32 = 1_048_576 / CYCLES_PER_SAMPLE;
+// About one second of interleaved stereo samples, the buffer is cleared
+// when nobody takes the samples (e.g. headless without audio)
+const MAX_BUFFERED_SAMPLES: usize = 2 * APU_SAMPLE_RATE as usize;
+
+/// Audio Processing Unit with two square, a wave and a noise channel
+#[derive(Debug)]
+pub struct Apu {
+    is_powered: bool, // NR52 bit 7
+    channel1: SquareChannel,
+    channel2: SquareChannel,
+    channel3: WaveChannel,
+    channel4: NoiseChannel,
+    volume: u8,  // NR50
+    panning: u8, // NR51
+
+    frame_step: u8, // 0-7, advanced at 512 Hz
+    last_div_bit: bool,
+
+    sample_cycles: u32,
+    sample_sum: (f32, f32),
+    samples: Vec<f32>, // interleaved left and right samples
+
+    // mono samples of each channel before panning and volume, used for recording
+    is_recording_channels: bool,
+    channel_sums: [f32; 4],
+    channel_samples: [Vec<f32>; 4],
+}
+
+impl Apu {
+    /// The state after the boot ROM played its sound
+    pub fn new() -> Self {
+        let mut apu = Apu {
+            is_powered: true,
+            channel1: SquareChannel::new(true),
+            channel2: SquareChannel::new(false),
+            channel3: WaveChannel::new(),
+            channel4: NoiseChannel::new(),
+            volume: 0x77,
+            panning: 0xF3,
+
+            frame_step: 0,
+            last_div_bit: false,
+
+            sample_cycles: 0,
+            sample_sum: (0.0, 0.0),
+            samples: Vec::new(),
+
+            is_recording_channels: false,
+            channel_sums: [0.0; 4],
+            channel_samples: Default::default(),
+        };
+        for (offset, data) in [
+            (0x10, 0x80),
+            (0x11, 0xBF),
+            (0x12, 0xF3),
+            (0x16, 0x3F),
+            (0x1B, 0xFF),
+            (0x1C, 0x9F),
+            (0x20, 0xFF),
+        ] {
+            apu.write(offset, data);
+        }
+        apu
+    }
+
+    /// `offset` is the lower byte of 0xFF10-0xFF3F
+    pub fn read(&self, offset: u8) -> u8 {
+        match offset {
+            0x10..=0x14 => self.channel1.read(offset - 0x10),
+            0x16..=0x19 => self.channel2.read(offset - 0x15),
+            0x1A..=0x1E => self.channel3.read(offset - 0x1A),
+            0x20..=0x23 => self.channel4.read(offset - 0x1F),
+            0x24 => self.volume,
+            0x25 => self.panning,
+            0x26 => self.read_status(),
+            0x30..=0x3F => self.channel3.read_wave_ram(offset - 0x30),
+            _ => 0xFF,
+        }
+    }
+
+    fn read_status(&self) -> u8 {
+        let mut status = 0x70;
+        if self.is_powered {
+            status |= 0x80;
+        }
+        let enabled = [
+            self.channel1.is_enabled,
+            self.channel2.is_enabled,
+            self.channel3.is_enabled,
+            self.channel4.is_enabled,
+        ];
+        for (bit, is_enabled) in enabled.iter().enumerate() {
+            if *is_enabled {
+                status |= 1 << bit;
+            }
+        }
+        status
+    }
+
+    pub fn write(&mut self, offset: u8, data: u8) {
+        // wave RAM and NR52 are still accessible while powered off
+        match offset {
+            0x26 => return self.set_power(data & 0x80 != 0),
+            0x30..=0x3F => return self.channel3.write_wave_ram(offset - 0x30, data),
+            _ if !self.is_powered => return,
+            _ => (),
+        }
+        match offset {
+            0x10..=0x14 => self.channel1.write(offset - 0x10, data),
+            0x16..=0x19 => self.channel2.write(offset - 0x15, data),
+            0x1A..=0x1E => self.channel3.write(offset - 0x1A, data),
+            0x20..=0x23 => self.channel4.write(offset - 0x1F, data),
+            0x24 => self.volume = data,
+            0x25 => self.panning = data,
+            _ => (),
+        }
+    }
+
+    fn set_power(&mut self, is_powered: bool) {
+        if self.is_powered && !is_powered {
+            // powering off clears all registers except wave RAM
+            let mut wave = WaveChannel::new();
+            for offset in 0..16 {
+                wave.write_wave_ram(offset, self.channel3.read_wave_ram(offset));
+            }
+            self.channel1 = SquareChannel::new(true);
+            self.channel2 = SquareChannel::new(false);
+            self.channel3 = wave;
+            self.channel4 = NoiseChannel::new();
+            self.volume = 0;
+            self.panning = 0;
+        } else if !self.is_powered && is_powered {
+            self.frame_step = 0;
+        }
+        self.is_powered = is_powered;
+    }
+
+    /// Takes the samples generated since the last call
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+
+    pub fn set_recording_channels(&mut self, is_recording_channels: bool) {
+        self.is_recording_channels = is_recording_channels;
+    }
+
+    /// Takes the samples of each channel generated since the last call
+    pub fn take_channel_samples(&mut self) -> [Vec<f32>; 4] {
+        std::mem::take(&mut self.channel_samples)
+    }
+
+    // One machine cycle
+    pub fn tick(bus: &mut Bus) {
+        // The frame sequencer is clocked by the falling edge of DIV bit 4
+        let div_bit = bus.timer.apu_clock_bit(bus.is_double_speed);
+        let apu = &mut bus.apu;
+        if apu.last_div_bit && !div_bit && apu.is_powered {
+            apu.step_frame_sequencer();
+        }
+        apu.last_div_bit = div_bit;
+
+        if apu.is_powered {
+            apu.channel1.tick(4);
+            apu.channel2.tick(4);
+            apu.channel3.tick(4);
+            apu.channel4.tick(4);
+        }
+        apu.mix();
+    }
+
+    fn step_frame_sequencer(&mut self) {
+        // length at 256 Hz, sweep at 128 Hz, envelope at 64 Hz
+        if self.frame_step.is_multiple_of(2) {
+            self.channel1.clock_length();
+            self.channel2.clock_length();
+            self.channel3.clock_length();
+            self.channel4.clock_length();
+        }
+        if self.frame_step == 2 || self.frame_step == 6 {
+            self.channel1.clock_sweep();
+        }
+        if self.frame_step == 7 {
+            self.channel1.envelope.clock();
+            self.channel2.envelope.clock();
+            self.channel4.envelope.clock();
+        }
+        self.frame_step = (self.frame_step + 1) % 8;
+    }
+
+    /// Analog output of each channel, -1.0 to 1.0
+    pub fn channel_outputs(&self) -> [f32; 4] {
+        [
+            dac(self.channel1.is_dac_enabled(), self.channel1.output()),
+            dac(self.channel2.is_dac_enabled(), self.channel2.output()),
+            dac(self.channel3.is_dac_enabled(), self.channel3.output()),
+            dac(self.channel4.is_dac_enabled(), self.channel4.output()),
+        ]
+    }
+
+    fn mix(&mut self) {
+        let (mut left, mut right) = (0.0, 0.0);
+        let outputs = self.channel_outputs();
+        if self.is_powered {
+            // NR51: bits 4-7 route channel 1-4 to the left, bits 0-3 to the right
+            for (channel, output) in outputs.iter().enumerate() {
+                if self.panning & (0x10 << channel) != 0 {
+                    left += output;
+                }
+                if self.panning & (0x01 << channel) != 0 {
+                    right += output;
+                }
+            }
+            // NR50: master volume 0-7 per side
+            left *= (((self.volume >> 4) & 0b111) + 1) as f32 / 8.0 / 4.0;
+            right *= ((self.volume & 0b111) + 1) as f32 / 8.0 / 4.0;
+        }
+
+        self.sample_sum.0 += left;
+        self.sample_sum.1 += right;
+        if self.is_recording_channels {
+            for (sum, output) in self.channel_sums.iter_mut().zip(outputs) {
+                *sum += output;
+            }
+        }
+        self.sample_cycles += 1;
+        if self.sample_cycles == CYCLES_PER_SAMPLE {
+            if self.samples.len() >= MAX_BUFFERED_SAMPLES {
+                self.samples.clear();
+                self.channel_samples = Default::default();
+            }
+            let count = CYCLES_PER_SAMPLE as f32;
+            self.samples.push(self.sample_sum.0 / count);
+            self.samples.push(self.sample_sum.1 / count);
+            if self.is_recording_channels {
+                for (samples, sum) in self.channel_samples.iter_mut().zip(self.channel_sums) {
+                    samples.push(sum / count);
+                }
+            }
+            self.sample_cycles = 0;
+            self.sample_sum = (0.0, 0.0);
+            self.channel_sums = [0.0; 4];
+        }
+    }
+}
+
+// Samples not taken yet and the recording setting belong to the frontend
+impl Snapshot for Apu {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.is_powered);
+        self.channel1.save_state(state);
+        self.channel2.save_state(state);
+        self.channel3.save_state(state);
+        self.channel4.save_state(state);
+        state.u8(self.volume);
+        state.u8(self.panning);
+        state.u8(self.frame_step);
+        state.bool(self.last_div_bit);
+        state.u32(self.sample_cycles);
+        state.f32(self.sample_sum.0);
+        state.f32(self.sample_sum.1);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.is_powered = state.bool()?;
+        self.channel1.load_state(state)?;
+        self.channel2.load_state(state)?;
+        self.channel3.load_state(state)?;
+        self.channel4.load_state(state)?;
+        self.volume = state.u8()?;
+        self.panning = state.u8()?;
+        self.frame_step = state.u8()? & 0b111;
+        self.last_div_bit = state.bool()?;
+        self.sample_cycles = state.u32()? % CYCLES_PER_SAMPLE;
+        self.sample_sum = (state.f32()?, state.f32()?);
+        self.channel_sums = [0.0; 4];
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn step_frame_sequencer(apu: &mut Apu, steps: usize) {
+        for _ in 0..steps {
+            apu.step_frame_sequencer();
+        }
+    }
+
+    #[test]
+    fn register_read_masks() {
+        let mut apu = Apu::new();
+        apu.write(0x11, 0b1011_1111);
+        assert_eq!(apu.read(0x11), 0b1011_1111);
+        apu.write(0x13, 0x12);
+        assert_eq!(apu.read(0x13), 0xFF);
+        assert_eq!(apu.read(0x15), 0xFF);
+        apu.write(0x1C, 0b0100_0000);
+        assert_eq!(apu.read(0x1C), 0b1101_1111);
+        apu.write(0x3F, 0xAB);
+        assert_eq!(apu.read(0x3F), 0xAB);
+    }
+
+    #[test]
+    fn trigger_and_length_counter() {
+        let mut apu = Apu::new();
+        apu.write(0x12, 0xF0); // full volume, DAC on
+        apu.write(0x11, 62); // length of 2
+        apu.write(0x14, 0b1100_0000); // trigger with length enabled
+        assert_eq!(apu.read(0x26) & 0b1, 1);
+
+        step_frame_sequencer(&mut apu, 2);
+        assert_eq!(apu.read(0x26) & 0b1, 1);
+        step_frame_sequencer(&mut apu, 1);
+        assert_eq!(apu.read(0x26) & 0b1, 0);
+    }
+
+    #[test]
+    fn dac_off_disables_channel() {
+        let mut apu = Apu::new();
+        apu.write(0x21, 0xF0);
+        apu.write(0x23, 0x80);
+        assert_eq!(apu.read(0x26) & 0b1000, 0b1000);
+        apu.write(0x21, 0x00);
+        assert_eq!(apu.read(0x26) & 0b1000, 0);
+    }
+
+    #[test]
+    fn sweep_overflow_disables_channel() {
+        let mut apu = Apu::new();
+        apu.write(0x12, 0xF0);
+        apu.write(0x10, 0b0001_0001); // period 1, add, shift 1
+        apu.write(0x13, 0x00);
+        apu.write(0x14, 0b1000_0101); // frequency 0x500
+        assert_eq!(apu.read(0x26) & 0b1, 1);
+        // the sweep sets 0x780, the following check of 0x780 + 0x3C0 overflows
+        step_frame_sequencer(&mut apu, 3);
+        assert_eq!(apu.read(0x26) & 0b1, 0);
+    }
+
+    #[test]
+    fn envelope_decreases_volume() {
+        let mut channel = SquareChannel::new(false);
+        channel.write(2, 0b0010_0001); // volume 2, decrease, period 1
+        channel.write(4, 0x80);
+        assert_eq!(channel.envelope.volume(), 2);
+        channel.envelope.clock();
+        assert_eq!(channel.envelope.volume(), 1);
+        channel.envelope.clock();
+        channel.envelope.clock();
+        assert_eq!(channel.envelope.volume(), 0);
+    }
+
+    #[test]
+    fn power_off_clears_registers_but_not_wave_ram() {
+        let mut apu = Apu::new();
+        apu.write(0x30, 0x12);
+        apu.write(0x24, 0x55);
+        apu.write(0x26, 0x00);
+        assert_eq!(apu.read(0x24), 0x00);
+        apu.write(0x24, 0x55);
+        assert_eq!(apu.read(0x24), 0x00);
+        assert_eq!(apu.read(0x26), 0x70);
+        assert_eq!(apu.read(0x30), 0x12);
+    }
+
+    #[test]
+    fn channel_samples_only_when_recording() {
+        let mut apu = Apu::new();
+        for _ in 0..CYCLES_PER_SAMPLE {
+            apu.mix();
+        }
+        assert!(apu.take_channel_samples().iter().all(Vec::is_empty));
+
+        apu.set_recording_channels(true);
+        for _ in 0..CYCLES_PER_SAMPLE {
+            apu.mix();
+        }
+        assert!(apu
+            .take_channel_samples()
+            .iter()
+            .all(|samples| samples.len() == 1));
+        assert_eq!(apu.take_samples().len(), 4);
+    }
+}
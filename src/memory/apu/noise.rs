@@ -0,0 +1,134 @@
+use super::channel::{Envelope, LengthCounter};
+use crate::util::state::{Snapshot, StateReader, StateWriter};
+
+const DIVISORS: [i32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+/// Channel 4, pseudo-random noise from a linear feedback shift register
+#[derive(Debug)]
+pub struct NoiseChannel {
+    pub is_enabled: bool,
+    pub length: LengthCounter,
+    pub envelope: Envelope,
+    polynomial: u8, // NR43
+    timer: i32,
+    lfsr: u16, // 15 bits
+}
+
+impl NoiseChannel {
+    pub fn new() -> Self {
+        NoiseChannel {
+            is_enabled: false,
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
+            polynomial: 0,
+            timer: 0,
+            lfsr: 0x7FFF,
+        }
+    }
+
+    fn period(&self) -> i32 {
+        let clock_shift = self.polynomial >> 4;
+        DIVISORS[(self.polynomial & 0b111) as usize] << clock_shift
+    }
+
+    // 7-bit mode gives a more regular, metallic sound
+    fn is_short_mode(&self) -> bool {
+        self.polynomial & 0b1000 != 0
+    }
+
+    pub fn read(&self, register: u8) -> u8 {
+        match register {
+            1 => 0xFF,
+            2 => self.envelope.register(),
+            3 => self.polynomial,
+            4 => {
+                if self.length.is_enabled {
+                    0xFF
+                } else {
+                    0xBF
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn write(&mut self, register: u8, data: u8) {
+        match register {
+            1 => self.length.load(data & 0x3F),
+            2 => {
+                self.envelope.set_register(data);
+                if !self.envelope.is_dac_enabled() {
+                    self.is_enabled = false;
+                }
+            }
+            3 => self.polynomial = data,
+            4 => {
+                self.length.is_enabled = data & 0x40 != 0;
+                if data & 0x80 != 0 {
+                    self.trigger();
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.is_enabled = self.envelope.is_dac_enabled();
+        self.length.trigger();
+        self.envelope.trigger();
+        self.timer = self.period();
+        self.lfsr = 0x7FFF;
+    }
+
+    pub fn tick(&mut self, cycles: i32) {
+        self.timer -= cycles;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            let feedback = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+            self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+            if self.is_short_mode() {
+                self.lfsr = (self.lfsr & !(1 << 6)) | (feedback << 6);
+            }
+        }
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.is_enabled = false;
+        }
+    }
+
+    /// Digital output 0-15
+    pub fn output(&self) -> u8 {
+        if self.is_enabled && self.lfsr & 1 == 0 {
+            self.envelope.volume()
+        } else {
+            0
+        }
+    }
+
+    pub fn is_dac_enabled(&self) -> bool {
+        self.envelope.is_dac_enabled()
+    }
+}
+
+impl Snapshot for NoiseChannel {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.is_enabled);
+        self.length.save_state(state);
+        self.envelope.save_state(state);
+        state.u8(self.polynomial);
+        state.i32(self.timer);
+        state.u16(self.lfsr);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.is_enabled = state.bool()?;
+        self.length.load_state(state)?;
+        self.envelope.load_state(state)?;
+        self.polynomial = state.u8()?;
+        self.timer = state.i32()?;
+        self.lfsr = state.u16()? & 0x7FFF;
+        Ok(())
+    }
+}
@@ -0,0 +1,257 @@
+use super::channel::{Envelope, LengthCounter};
+use crate::util::state::{Snapshot, StateReader, StateWriter};
+
+const DUTY_CYCLES: [u8; 4] = [
+    0b0000_0001, // 12.5%
+    0b1000_0001, // 25%
+    0b1000_0111, // 50%
+    0b0111_1110, // 75%
+];
+
+/// Frequency sweep of channel 1 (NR10)
+#[derive(Debug)]
+struct Sweep {
+    register: u8,
+    is_enabled: bool,
+    shadow_frequency: u16,
+    timer: u8,
+    has_negated: bool, // a calculation in negate mode happened since the trigger
+}
+
+impl Sweep {
+    fn new() -> Self {
+        Sweep {
+            register: 0,
+            is_enabled: false,
+            shadow_frequency: 0,
+            timer: 0,
+            has_negated: false,
+        }
+    }
+
+    fn period(&self) -> u8 {
+        (self.register >> 4) & 0b111
+    }
+
+    fn is_negating(&self) -> bool {
+        self.register & 0b1000 != 0
+    }
+
+    fn shift(&self) -> u8 {
+        self.register & 0b111
+    }
+
+    fn reload_timer(&mut self) {
+        // a period of 0 is treated as 8
+        self.timer = if self.period() == 0 { 8 } else { self.period() };
+    }
+
+    /// Returns None if the new frequency overflows 11 bits
+    fn calculate(&mut self) -> Option<u16> {
+        let delta = self.shadow_frequency >> self.shift();
+        let frequency = if self.is_negating() {
+            self.has_negated = true;
+            self.shadow_frequency - delta
+        } else {
+            self.shadow_frequency + delta
+        };
+        (frequency <= 2047).then_some(frequency)
+    }
+}
+
+/// Channels 1 and 2, channel 2 has no sweep
+#[derive(Debug)]
+pub struct SquareChannel {
+    pub is_enabled: bool,
+    sweep: Option<Sweep>,
+    duty: u8,
+    duty_step: u8,
+    pub length: LengthCounter,
+    pub envelope: Envelope,
+    frequency: u16, // 11 bits
+    timer: i32,
+}
+
+impl SquareChannel {
+    pub fn new(has_sweep: bool) -> Self {
+        SquareChannel {
+            is_enabled: false,
+            sweep: has_sweep.then(Sweep::new),
+            duty: 0,
+            duty_step: 0,
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
+            frequency: 0,
+            timer: 0,
+        }
+    }
+
+    fn period(&self) -> i32 {
+        (2048 - self.frequency as i32) * 4
+    }
+
+    pub fn read(&self, register: u8) -> u8 {
+        match register {
+            0 => self
+                .sweep
+                .as_ref()
+                .map_or(0xFF, |sweep| sweep.register | 0x80),
+            1 => (self.duty << 6) | 0x3F,
+            2 => self.envelope.register(),
+            3 => 0xFF,
+            4 => {
+                if self.length.is_enabled {
+                    0xFF
+                } else {
+                    0xBF
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn write(&mut self, register: u8, data: u8) {
+        match register {
+            0 => {
+                if let Some(sweep) = &mut self.sweep {
+                    sweep.register = data & 0x7F;
+                    // leaving negate mode after a negated calculation disables the channel
+                    if sweep.has_negated && !sweep.is_negating() {
+                        self.is_enabled = false;
+                    }
+                }
+            }
+            1 => {
+                self.duty = data >> 6;
+                self.length.load(data & 0x3F);
+            }
+            2 => {
+                self.envelope.set_register(data);
+                if !self.envelope.is_dac_enabled() {
+                    self.is_enabled = false;
+                }
+            }
+            3 => self.frequency = (self.frequency & 0x700) | data as u16,
+            4 => {
+                self.frequency = (self.frequency & 0xFF) | ((data as u16 & 0b111) << 8);
+                self.length.is_enabled = data & 0x40 != 0;
+                if data & 0x80 != 0 {
+                    self.trigger();
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.is_enabled = self.envelope.is_dac_enabled();
+        self.length.trigger();
+        self.envelope.trigger();
+        self.timer = self.period();
+
+        if let Some(sweep) = &mut self.sweep {
+            sweep.shadow_frequency = self.frequency;
+            sweep.reload_timer();
+            sweep.has_negated = false;
+            sweep.is_enabled = sweep.period() != 0 || sweep.shift() != 0;
+            if sweep.shift() != 0 && sweep.calculate().is_none() {
+                self.is_enabled = false;
+            }
+        }
+    }
+
+    pub fn tick(&mut self, cycles: i32) {
+        self.timer -= cycles;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            self.duty_step = (self.duty_step + 1) % 8;
+        }
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.is_enabled = false;
+        }
+    }
+
+    /// Clocked at 128 Hz
+    pub fn clock_sweep(&mut self) {
+        let Some(sweep) = &mut self.sweep else {
+            return;
+        };
+        if sweep.timer > 0 {
+            sweep.timer -= 1;
+        }
+        if sweep.timer != 0 {
+            return;
+        }
+        sweep.reload_timer();
+        if !sweep.is_enabled || sweep.period() == 0 {
+            return;
+        }
+        match sweep.calculate() {
+            Some(frequency) if sweep.shift() != 0 => {
+                sweep.shadow_frequency = frequency;
+                self.frequency = frequency;
+                // the new frequency is checked for an overflow again
+                if sweep.calculate().is_none() {
+                    self.is_enabled = false;
+                }
+            }
+            Some(_) => (),
+            None => self.is_enabled = false,
+        }
+    }
+
+    /// Digital output 0-15
+    pub fn output(&self) -> u8 {
+        let is_high = DUTY_CYCLES[self.duty as usize] & (1 << self.duty_step) != 0;
+        if self.is_enabled && is_high {
+            self.envelope.volume()
+        } else {
+            0
+        }
+    }
+
+    pub fn is_dac_enabled(&self) -> bool {
+        self.envelope.is_dac_enabled()
+    }
+}
+
+impl Snapshot for SquareChannel {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.is_enabled);
+        // only channel 1 has a sweep
+        if let Some(sweep) = &self.sweep {
+            state.u8(sweep.register);
+            state.bool(sweep.is_enabled);
+            state.u16(sweep.shadow_frequency);
+            state.u8(sweep.timer);
+            state.bool(sweep.has_negated);
+        }
+        state.u8(self.duty);
+        state.u8(self.duty_step);
+        self.length.save_state(state);
+        self.envelope.save_state(state);
+        state.u16(self.frequency);
+        state.i32(self.timer);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.is_enabled = state.bool()?;
+        if let Some(sweep) = &mut self.sweep {
+            sweep.register = state.u8()?;
+            sweep.is_enabled = state.bool()?;
+            sweep.shadow_frequency = state.u16()? & 0x7FF;
+            sweep.timer = state.u8()?;
+            sweep.has_negated = state.bool()?;
+        }
+        self.duty = state.u8()? & 0b11;
+        self.duty_step = state.u8()? & 0b111;
+        self.length.load_state(state)?;
+        self.envelope.load_state(state)?;
+        self.frequency = state.u16()? & 0x7FF;
+        self.timer = state.i32()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,153 @@
+use super::channel::LengthCounter;
+use crate::util::state::{Snapshot, StateReader, StateWriter};
+
+const WAVE_RAM_SIZE: usize = 16;
+
+/// Channel 3, plays 32 4-bit samples from wave RAM
+#[derive(Debug)]
+pub struct WaveChannel {
+    pub is_enabled: bool,
+    is_dac_enabled: bool,
+    pub length: LengthCounter,
+    volume_code: u8, // 0: mute, 1: 100%, 2: 50%, 3: 25%
+    frequency: u16,
+    timer: i32,
+    position: u8, // current 4-bit sample
+    wave_ram: [u8; WAVE_RAM_SIZE],
+}
+
+impl WaveChannel {
+    pub fn new() -> Self {
+        WaveChannel {
+            is_enabled: false,
+            is_dac_enabled: false,
+            length: LengthCounter::new(256),
+            volume_code: 0,
+            frequency: 0,
+            timer: 0,
+            position: 0,
+            wave_ram: [0; WAVE_RAM_SIZE],
+        }
+    }
+
+    fn period(&self) -> i32 {
+        (2048 - self.frequency as i32) * 2
+    }
+
+    pub fn read(&self, register: u8) -> u8 {
+        match register {
+            0 => {
+                if self.is_dac_enabled {
+                    0xFF
+                } else {
+                    0x7F
+                }
+            }
+            1 => 0xFF,
+            2 => (self.volume_code << 5) | 0x9F,
+            3 => 0xFF,
+            4 => {
+                if self.length.is_enabled {
+                    0xFF
+                } else {
+                    0xBF
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn write(&mut self, register: u8, data: u8) {
+        match register {
+            0 => {
+                self.is_dac_enabled = data & 0x80 != 0;
+                if !self.is_dac_enabled {
+                    self.is_enabled = false;
+                }
+            }
+            1 => self.length.load(data),
+            2 => self.volume_code = (data >> 5) & 0b11,
+            3 => self.frequency = (self.frequency & 0x700) | data as u16,
+            4 => {
+                self.frequency = (self.frequency & 0xFF) | ((data as u16 & 0b111) << 8);
+                self.length.is_enabled = data & 0x40 != 0;
+                if data & 0x80 != 0 {
+                    self.trigger();
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn read_wave_ram(&self, offset: u8) -> u8 {
+        self.wave_ram[offset as usize]
+    }
+
+    pub fn write_wave_ram(&mut self, offset: u8, data: u8) {
+        self.wave_ram[offset as usize] = data;
+    }
+
+    fn trigger(&mut self) {
+        self.is_enabled = self.is_dac_enabled;
+        self.length.trigger();
+        self.timer = self.period();
+        self.position = 0;
+    }
+
+    pub fn tick(&mut self, cycles: i32) {
+        self.timer -= cycles;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            self.position = (self.position + 1) % 32;
+        }
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.is_enabled = false;
+        }
+    }
+
+    /// Digital output 0-15
+    pub fn output(&self) -> u8 {
+        if !self.is_enabled || self.volume_code == 0 {
+            return 0;
+        }
+        // the upper nibble is played first
+        let byte = self.wave_ram[self.position as usize / 2];
+        let sample = if self.position.is_multiple_of(2) {
+            byte >> 4
+        } else {
+            byte & 0x0F
+        };
+        sample >> (self.volume_code - 1)
+    }
+
+    pub fn is_dac_enabled(&self) -> bool {
+        self.is_dac_enabled
+    }
+}
+
+impl Snapshot for WaveChannel {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.is_enabled);
+        state.bool(self.is_dac_enabled);
+        self.length.save_state(state);
+        state.u8(self.volume_code);
+        state.u16(self.frequency);
+        state.i32(self.timer);
+        state.u8(self.position);
+        state.bytes(&self.wave_ram);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.is_enabled = state.bool()?;
+        self.is_dac_enabled = state.bool()?;
+        self.length.load_state(state)?;
+        self.volume_code = state.u8()? & 0b11;
+        self.frequency = state.u16()? & 0x7FF;
+        self.timer = state.i32()?;
+        self.position = state.u8()? % (WAVE_RAM_SIZE as u8 * 2);
+        state.bytes_into(&mut self.wave_ram)
+    }
+}
@@ -1,22 +1,35 @@
+use super::apu::Apu;
 use super::cartridge::Cartridge;
 use super::lcd::{Lcd, Palette};
 use super::oam::Oam;
 use super::ppu::Ppu;
-use crate::memory::dma::Dma;
-use crate::memory::interrupts::InterruptHandler;
+use crate::memory::dma::{Dma, Hdma};
+use crate::memory::interrupts::{Interrupt, InterruptHandler};
+use crate::memory::joypad::Joypad;
+use crate::memory::serial::Serial;
+use crate::memory::sgb::Sgb;
 use crate::memory::timer::Timer;
+use crate::model::Model;
 use crate::util::helper::split_u16;
+use crate::util::state::{Snapshot, StateReader, StateWriter};
 
 const V_RAM_SIZE: usize = 8192;
-const W_RAM_SIZE: usize = 8192;
+const V_RAM_BANKS: usize = 2; // CGB
+const W_RAM_BANK_SIZE: usize = 4096;
+const W_RAM_BANKS: usize = 8; // CGB, the DMG has 2
 const H_RAM_SIZE: usize = 127;
 
+pub const BOOT_ROM_SIZE: usize = 0x100;
+
 const CART_START: u16 = 0;
 const CART_END: u16 = 0x7FFF;
 
 const V_RAM_START: u16 = 0x8000;
 const V_RAM_END: u16 = 0x9FFF;
 
+const EXT_RAM_START: u16 = 0xA000;
+const EXT_RAM_END: u16 = 0xBFFF;
+
 const W_RAM_START: u16 = 0xC000;
 const W_RAM_END: u16 = 0xDFFF;
 
@@ -31,50 +44,132 @@ const H_RAM_END: u16 = 0xFFFE;
 
 const INTERRUPT_ENABLED: u16 = 0xFFFF;
 
+// IO registers that only exist in CGB mode
+fn is_cgb_register(offset: u8) -> bool {
+    matches!(offset, 0x4D | 0x4F | 0x51..=0x55 | 0x68..=0x70)
+}
+
 pub struct Bus {
+    pub model: Model,
+    pub is_cgb_mode: bool,     // a CGB running a cartridge with color support
+    pub is_sgb_mode: bool,     // an SGB running a cartridge with SGB support
     pub cartridge: Cartridge,  // mapped in Cartridge data
     pub lcd: Lcd,              // LCD registers
     pub timer: Timer,          // timer registers
     pub int: InterruptHandler, // requested and pending interrupts
     pub dma: Dma,              // Data Transfer unit
+    pub hdma: Hdma,            // CGB VRAM DMA
     pub oam: Oam,              // Object Attribute Memory
     pub ppu: Ppu,              // Pixel Processing Unit
+    pub joypad: Joypad,        // P1 button matrix
+    pub apu: Apu,              // Audio Processing Unit
+    pub serial: Serial,        // link port
+    pub sgb: Sgb,              // SGB packets, palettes and border
+    pub is_double_speed: bool, // KEY1 bit 7, the CPU runs at 2 MHz
+    pub is_speed_switch_armed: bool, // KEY1 bit 0, STOP switches the speed
 
-    v_ram: [u8; V_RAM_SIZE], // video ram
-    w_ram: [u8; W_RAM_SIZE], // work ram
-    h_ram: [u8; H_RAM_SIZE], // high ram
+    boot_rom: Option<Vec<u8>>, // mapped over 0x0000-0x00FF until 0xFF50 is written
+    v_ram: Vec<u8>,            // video ram
+    v_ram_bank: u8,            // VBK
+    w_ram: Vec<u8>,            // work ram
+    w_ram_bank: u8,            // SVBK, the bank at 0xD000-0xDFFF
+    h_ram: [u8; H_RAM_SIZE],   // high ram
 }
 
 impl Bus {
-    pub fn new(cartridge: Cartridge, ppu: Ppu) -> Self {
+    pub fn new(cartridge: Cartridge) -> Self {
         Bus {
+            model: Model::default(),
+            is_cgb_mode: false,
+            is_sgb_mode: false,
             cartridge,
             lcd: Lcd::new(),
             timer: Timer::new(),
             int: InterruptHandler::new(),
             dma: Dma::new(),
+            hdma: Hdma::new(),
             oam: Oam::new(),
-            ppu,
+            ppu: Ppu::new(),
+            joypad: Joypad::new(),
+            apu: Apu::new(),
+            serial: Serial::new(),
+            sgb: Sgb::new(),
+            is_double_speed: false,
+            is_speed_switch_armed: false,
 
-            v_ram: [0; V_RAM_SIZE],
-            w_ram: [0; W_RAM_SIZE],
+            boot_rom: None,
+            v_ram: vec![0; V_RAM_BANKS * V_RAM_SIZE],
+            v_ram_bank: 0,
+            w_ram: vec![0; W_RAM_BANKS * W_RAM_BANK_SIZE],
+            w_ram_bank: 1,
             h_ram: [0; H_RAM_SIZE],
         }
     }
 
+    /// Puts the IO registers into the state the boot ROM of `model` leaves behind
+    pub fn set_model(&mut self, model: Model) {
+        self.model = model;
+        self.is_cgb_mode = model.is_cgb() && self.cartridge.supports_cgb();
+        self.is_sgb_mode = model.is_sgb() && self.cartridge.supports_sgb();
+        self.sgb = Sgb::new();
+        self.v_ram_bank = 0;
+        self.w_ram_bank = 1;
+        self.is_double_speed = false;
+        self.is_speed_switch_armed = false;
+        self.hdma = Hdma::new();
+        self.lcd = Lcd::new();
+        self.timer = Timer::new();
+        self.timer.set_divider(model.divider());
+        // TAC reads 0xF8, the timer is stopped
+        self.timer.set_control(0);
+        // the last VBlank of the logo animation is still pending
+        self.int.set_requested(Interrupt::VBlank.bit());
+        self.apu = Apu::new();
+    }
+
+    /// Maps the boot ROM and puts the registers into their power-on state
+    pub fn map_boot_rom(&mut self, boot_rom: Vec<u8>) {
+        self.boot_rom = Some(boot_rom);
+        self.timer.set_divider(0);
+        self.int.set_requested(0);
+        self.lcd.control = 0;
+        self.lcd.bg_palette = 0;
+        self.lcd.obj_palette_0 = 0;
+        self.lcd.obj_palette_1 = 0;
+        self.timer.set_control(0);
+        self.apu.write(0x26, 0);
+    }
+
+    /// VRAM as the PPU sees it, independent of VBK
+    pub fn read_vram(&self, bank: u8, address: u16) -> u8 {
+        self.v_ram[bank as usize * V_RAM_SIZE + (address - V_RAM_START) as usize]
+    }
+
+    fn v_ram_index(&self, address: u16) -> usize {
+        self.v_ram_bank as usize * V_RAM_SIZE + (address - V_RAM_START) as usize
+    }
+
+    // 0xC000-0xCFFF is always bank 0, 0xD000-0xDFFF the bank selected by SVBK
+    fn w_ram_index(&self, address: u16) -> usize {
+        let offset = (address - W_RAM_START) as usize;
+        if offset < W_RAM_BANK_SIZE {
+            offset
+        } else {
+            self.w_ram_bank as usize * W_RAM_BANK_SIZE + offset - W_RAM_BANK_SIZE
+        }
+    }
+
     // https://gbdev.io/pandocs/Memory_Map.html
     pub fn read(&self, address: u16) -> u8 {
         // println!("Reading bus at {:#x}", address);
         match address {
-            CART_START..=CART_END => self.cartridge.read(address as usize),
-            V_RAM_START..=V_RAM_END => {
-                let v_ram_address = (address - V_RAM_START) as usize;
-                self.v_ram[v_ram_address]
-            }
-            W_RAM_START..=W_RAM_END => {
-                let w_ram_address = (address - W_RAM_START) as usize;
-                self.w_ram[w_ram_address]
+            0..=0xFF if self.boot_rom.is_some() => {
+                self.boot_rom.as_ref().unwrap()[address as usize]
             }
+            CART_START..=CART_END => self.cartridge.read(address),
+            V_RAM_START..=V_RAM_END => self.v_ram[self.v_ram_index(address)],
+            EXT_RAM_START..=EXT_RAM_END => self.cartridge.read_ram(address),
+            W_RAM_START..=W_RAM_END => self.w_ram[self.w_ram_index(address)],
             OAM_START..=OAM_END => {
                 let oam_address = address - OAM_START;
                 let (_, lower) = split_u16(oam_address);
@@ -95,15 +190,26 @@ impl Bus {
 
     fn read_mapped_io_register(&self, offset: u8) -> u8 {
         match offset {
+            0x00 => match self.sgb.joypad_id() {
+                // with both lines deselected the SGB returns the current joypad
+                Some(id) if self.is_sgb_mode && self.joypad.read() & 0x30 == 0x30 => 0xFF - id,
+                _ => self.joypad.read(),
+            },
+            0x01 => self.serial.data(),
+            0x02 => self.serial.control(),
+
             0x04 => self.timer.divider(),
             0x05 => self.timer.counter(),
             0x06 => self.timer.modulo(),
-            0x07 => self.timer.control(),
+            // the upper 5 bits of TAC are unused and read as 1
+            0x07 => self.timer.control() | 0xF8,
 
             0x0F => self.int.requested(),
 
+            0x10..=0x3F => self.apu.read(offset),
+
             0x40 => self.lcd.control,
-            0x41 => self.lcd.status,
+            0x41 => self.lcd.read_status(),
             0x42 => self.lcd.scroll_y,
             0x43 => self.lcd.scroll_x,
             0x44 => self.lcd.ly,
@@ -120,6 +226,20 @@ impl Bus {
             0x49 => self.lcd.obj_palette_1,
             0x4A => self.lcd.win_y,
             0x4B => self.lcd.win_x,
+            0x50 => 0xFF,
+
+            // CGB registers, unmapped on the DMG
+            _ if !self.is_cgb_mode && is_cgb_register(offset) => 0xFF,
+            0x4D => 0x7E | (self.is_double_speed as u8) << 7 | self.is_speed_switch_armed as u8,
+            0x4F => 0xFE | self.v_ram_bank,
+            0x51..=0x54 => 0xFF,
+            0x55 => self.hdma.read_control(),
+            0x68 => self.lcd.bg_colors.read_spec(),
+            0x69 => self.lcd.bg_colors.read_data(),
+            0x6A => self.lcd.obj_colors.read_spec(),
+            0x6B => self.lcd.obj_colors.read_data(),
+            0x6C => 0xFE | self.lcd.object_priority,
+            0x70 => 0xF8 | self.w_ram_bank,
             _ => {
                 eprintln!("{} is not mapped yet!", offset);
                 0
@@ -132,12 +252,13 @@ impl Bus {
         match address {
             CART_START..=CART_END => self.cartridge.write(address, data),
             V_RAM_START..=V_RAM_END => {
-                let v_ram_address = (address - V_RAM_START) as usize;
+                let v_ram_address = self.v_ram_index(address);
                 self.v_ram[v_ram_address] = data;
             }
+            EXT_RAM_START..=EXT_RAM_END => self.cartridge.write_ram(address, data),
             W_RAM_START..=W_RAM_END => {
-                let h_ram_address = (address - W_RAM_START) as usize;
-                self.w_ram[h_ram_address] = data
+                let w_ram_address = self.w_ram_index(address);
+                self.w_ram[w_ram_address] = data
             }
             OAM_START..=OAM_END => {
                 let (_, lower) = split_u16(address);
@@ -161,6 +282,18 @@ impl Bus {
 
     fn write_mapped_io_register(&mut self, offset: u8, data: u8) {
         match offset {
+            0x00 => {
+                if self.is_sgb_mode {
+                    self.sgb.write_joypad(data);
+                }
+                let is_newly_pressed = self.joypad.write(data);
+                if is_newly_pressed {
+                    self.int.request_interrupt(Interrupt::Joypad);
+                }
+            }
+            0x01 => self.serial.set_data(data),
+            0x02 => self.serial.set_control(data),
+
             0x04 => self.timer.reset_divider(),
             0x05 => self.timer.set_counter(data),
             0x06 => self.timer.set_modulo(data),
@@ -168,11 +301,13 @@ impl Bus {
 
             0x0F => self.int.set_requested(data),
 
+            0x10..=0x3F => self.apu.write(offset, data),
+
             0x40 => self.lcd.control = data,
-            0x41 => self.lcd.status = data,
+            0x41 => self.lcd.write_status(data),
             0x42 => self.lcd.scroll_y = data,
             0x43 => self.lcd.scroll_x = data,
-            0x44 => self.lcd.ly = data,
+            0x44 => (), // LY is read-only
             0x45 => self.lcd.ly_compare = data,
             0x46 => self.dma.start(data),
             0x47 => self.lcd.update_palette(data, Palette::Background),
@@ -180,7 +315,94 @@ impl Bus {
             0x49 => self.lcd.update_palette(data, Palette::Obj1),
             0x4A => self.lcd.win_y = data,
             0x4B => self.lcd.win_x = data,
+            // the boot ROM unmaps itself before jumping to the cartridge
+            0x50 if data != 0 => self.boot_rom = None,
+
+            _ if !self.is_cgb_mode && is_cgb_register(offset) => (),
+            0x4D => self.is_speed_switch_armed = data & 1 != 0,
+            0x4F => self.v_ram_bank = data & 1,
+            0x51..=0x55 => {
+                self.hdma.write(offset, data);
+                // with the LCD off an HBlank DMA copies its first block right away
+                if offset == 0x55 && !self.lcd.lcd_enable() {
+                    self.hdma.start_hblank_block();
+                }
+            }
+            0x68 => self.lcd.bg_colors.write_spec(data),
+            0x69 => self.lcd.bg_colors.write_data(data),
+            0x6A => self.lcd.obj_colors.write_spec(data),
+            0x6B => self.lcd.obj_colors.write_data(data),
+            0x6C => self.lcd.object_priority = data & 1,
+            // bank 0 selects bank 1
+            0x70 => self.w_ram_bank = (data & 0b111).max(1),
             _ => (),
         }
     }
 }
+
+impl Snapshot for Bus {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(self.model.to_string().as_bytes());
+        state.bool(self.is_cgb_mode);
+        state.bool(self.is_sgb_mode);
+        state.bool(self.is_double_speed);
+        state.bool(self.is_speed_switch_armed);
+        self.cartridge.save_state(state);
+        self.lcd.save_state(state);
+        self.timer.save_state(state);
+        self.int.save_state(state);
+        self.dma.save_state(state);
+        self.hdma.save_state(state);
+        self.oam.save_state(state);
+        self.ppu.save_state(state);
+        self.joypad.save_state(state);
+        self.apu.save_state(state);
+        self.serial.save_state(state);
+        self.sgb.save_state(state);
+
+        state.bytes(self.boot_rom.as_deref().unwrap_or_default());
+        state.bytes(&self.v_ram);
+        state.u8(self.v_ram_bank);
+        state.bytes(&self.w_ram);
+        state.u8(self.w_ram_bank);
+        state.bytes(&self.h_ram);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        let model = String::from_utf8_lossy(&state.bytes()?).parse()?;
+        self.model = model;
+        self.is_cgb_mode = state.bool()? && model.is_cgb();
+        self.is_sgb_mode = state.bool()? && model.is_sgb();
+        self.is_double_speed = state.bool()? && self.is_cgb_mode;
+        self.is_speed_switch_armed = state.bool()?;
+        self.cartridge.load_state(state)?;
+        self.lcd.load_state(state)?;
+        self.timer.load_state(state)?;
+        self.int.load_state(state)?;
+        self.dma.load_state(state)?;
+        self.hdma.load_state(state)?;
+        self.oam.load_state(state)?;
+        self.ppu.load_state(state)?;
+        self.joypad.load_state(state)?;
+        self.apu.load_state(state)?;
+        self.serial.load_state(state)?;
+        self.sgb.load_state(state)?;
+
+        let boot_rom = state.bytes()?;
+        self.boot_rom = match boot_rom.len() {
+            0 => None,
+            BOOT_ROM_SIZE => Some(boot_rom),
+            size => return Err(format!("Invalid boot ROM size {}", size)),
+        };
+        state.bytes_into(&mut self.v_ram)?;
+        self.v_ram_bank = state.u8()? & 1;
+        state.bytes_into(&mut self.w_ram)?;
+        self.w_ram_bank = (state.u8()? & 0b111).max(1);
+        state.bytes_into(&mut self.h_ram)?;
+        // the SGB picture is not part of the state
+        if self.is_sgb_mode {
+            self.sgb.render(self.ppu.shades());
+        }
+        Ok(())
+    }
+}
@@ -1,31 +1,36 @@
-use std::{fs::File, io::Read};
+use super::mbc::{
+    mbc1::Mbc1,
+    mbc2::{Mbc2, MBC2_RAM_SIZE},
+    mbc3::Mbc3,
+    mbc5::Mbc5,
+    rtc::{Rtc, RtcClock, RTC_SAVE_SIZE},
+    MemoryBankController, RAM_BANK_SIZE, ROM_BANK_SIZE,
+};
+use crate::util::state::{Snapshot, StateReader, StateWriter};
+use std::{
+    fs::{self, File},
+    io::Read,
+    path::{Path, PathBuf},
+};
 
-#[derive(Debug)]
-pub enum MemoryBankController {
-    RomOnly,
-    MBC1,
-}
-
-#[derive(Debug)]
-pub enum BankingMode {
-    Rom,
-    Ram,
-}
+const LOGO_START: usize = 0x0104;
+const LOGO_END: usize = 0x0134;
 
 #[derive(Debug)]
 pub struct Cartridge {
     pub data: Vec<u8>,
     pub title: String,
     pub mbc: MemoryBankController,
-    pub rom_banks: u8, // number of 32 KiB ROM banks
-    pub ram_banks: u8, // number of 8 KiB RAM banks
+    pub rom_banks: u16, // number of 32 KiB ROM banks
+    pub ram_banks: u8,  // number of 8 KiB RAM banks
+    pub ram: Vec<u8>,   // external RAM at 0xA000-0xBFFF
 
-    // MBC1 related, this may be refactored to it's own struct
-    is_ram_enabled: bool,
-    banking_mode: BankingMode,
+    has_battery: bool,
+    save_path: Option<PathBuf>, // where battery-backed RAM is persisted
+    is_ram_dirty: bool,         // RAM was written since the last save
 }
 
-fn read_string(data: &Vec<u8>, start_index: usize, length: usize) -> String {
+fn read_string(data: &[u8], start_index: usize, length: usize) -> String {
     let mut string = String::new();
     for i in start_index..start_index + length {
         let byte = *data.get(i).unwrap();
@@ -37,16 +42,63 @@ fn read_string(data: &Vec<u8>, start_index: usize, length: usize) -> String {
     string
 }
 
+// MBC1M multicarts are 1 MiB and contain another game with
+// its own header (and Nintendo logo) starting at bank 0x10
+fn is_mbc1_multicart(data: &[u8]) -> bool {
+    let second_header = 0x10 * ROM_BANK_SIZE;
+    data.len() == 0x10_0000
+        && data[LOGO_START..LOGO_END] == data[second_header + LOGO_START..second_header + LOGO_END]
+}
+
+fn has_battery(cartridge_type: u8) -> bool {
+    matches!(
+        cartridge_type,
+        0x03 | 0x06 | 0x09 | 0x0D | 0x0F | 0x10 | 0x13 | 0x1B | 0x1E | 0x22 | 0xFF
+    )
+}
+
 impl Cartridge {
     pub fn load_from_file(cartridge_path: &str) -> Result<Cartridge, String> {
         let mut file = File::open(cartridge_path).map_err(|e| e.to_string())?;
         let mut buffer = Vec::new();
         file.read_to_end(&mut buffer).map_err(|e| e.to_string())?;
+        let mut cartridge = Self::from_bytes(buffer)?;
+
+        if cartridge.has_battery {
+            let save_path = Path::new(cartridge_path).with_extension("sav");
+            if save_path.exists() {
+                cartridge.load_save(&save_path)?;
+                eprintln!("Loaded save file {}", save_path.display());
+            }
+            cartridge.save_path = Some(save_path);
+        }
+        Ok(cartridge)
+    }
+
+    pub fn from_bytes(buffer: Vec<u8>) -> Result<Cartridge, String> {
+        if buffer.len() < 0x0150 {
+            return Err(format!(
+                "Cartridge is too small to contain a header ({} bytes)",
+                buffer.len()
+            ));
+        }
 
         let title = read_string(&buffer, 0x0134, 16);
-        let mbc = match *buffer.get(0x0147).unwrap() {
-            0 => MemoryBankController::RomOnly,
-            _ => MemoryBankController::MBC1,
+        let cartridge_type = *buffer.get(0x0147).unwrap();
+        let mbc = match cartridge_type {
+            0x00 | 0x08 | 0x09 => MemoryBankController::RomOnly,
+            0x01..=0x03 => MemoryBankController::Mbc1(Mbc1::new(is_mbc1_multicart(&buffer))),
+            0x05 | 0x06 => MemoryBankController::Mbc2(Mbc2::new()),
+            0x0F | 0x10 => MemoryBankController::Mbc3(Mbc3::new(true)),
+            0x11..=0x13 => MemoryBankController::Mbc3(Mbc3::new(false)),
+            0x19..=0x1B => MemoryBankController::Mbc5(Mbc5::new(false)),
+            0x1C..=0x1E => MemoryBankController::Mbc5(Mbc5::new(true)),
+            _ => {
+                return Err(format!(
+                    "Unsupported cartridge type {:#04X}",
+                    cartridge_type
+                ))
+            }
         };
         let rom_banks = 1 << *buffer.get(0x0148).unwrap();
         let ram_banks = match *buffer.get(0x0149).unwrap() {
@@ -57,6 +109,10 @@ impl Cartridge {
             5 => 8,
             _ => 0,
         };
+        let ram_size = match mbc {
+            MemoryBankController::Mbc2(_) => MBC2_RAM_SIZE,
+            _ => ram_banks as usize * RAM_BANK_SIZE,
+        };
         eprintln!(
             "Cartridge info: {} {:?} {} {}",
             title, mbc, rom_banks, ram_banks
@@ -67,20 +123,97 @@ impl Cartridge {
             mbc,
             rom_banks,
             ram_banks,
+            ram: vec![0; ram_size],
+
+            has_battery: has_battery(cartridge_type),
+            save_path: None,
+            is_ram_dirty: false,
         })
     }
 
-    pub fn read(&self, index: usize) -> u8 {
-        if index < self.data.len() {
-            self.data[index]
+    /// 0x80 in the CGB flag marks dual mode, 0xC0 CGB-only cartridges
+    pub fn supports_cgb(&self) -> bool {
+        self.data[0x0143] & 0x80 != 0
+    }
+
+    /// The SGB only accepts packets from cartridges with the SGB flag and the new licensee code
+    pub fn supports_sgb(&self) -> bool {
+        self.data[0x0146] == 0x03 && self.data[0x014B] == 0x33
+    }
+
+    pub fn header_checksum(&self) -> u8 {
+        self.data[0x014D]
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        self.mbc.read_rom(&self.data, address)
+    }
+
+    pub fn write(&mut self, address: u16, data: u8) {
+        self.mbc.write_register(address, data);
+    }
+
+    pub fn read_ram(&self, address: u16) -> u8 {
+        self.mbc.read_ram(&self.ram, address)
+    }
+
+    pub fn write_ram(&mut self, address: u16, data: u8) {
+        self.mbc.write_ram(&mut self.ram, address, data);
+        self.is_ram_dirty = true;
+    }
+
+    // The raw layout other emulators use: the RAM followed by
+    // the 48 byte RTC block for MBC3 cartridges with a clock
+    fn load_save(&mut self, save_path: &Path) -> Result<(), String> {
+        let bytes = fs::read(save_path).map_err(|e| e.to_string())?;
+        let ram_size = self.ram.len().min(bytes.len());
+        self.ram[..ram_size].copy_from_slice(&bytes[..ram_size]);
+        if bytes.len() >= self.ram.len() + RTC_SAVE_SIZE {
+            if let Some(rtc) = self.rtc() {
+                rtc.load(&bytes[ram_size..])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes battery-backed RAM and the RTC to the save file
+    pub fn save(&mut self) -> Result<(), String> {
+        let Some(save_path) = self.save_path.clone() else {
+            return Ok(());
+        };
+        let mut bytes = self.ram.clone();
+        if let Some(rtc) = self.rtc() {
+            bytes.extend(rtc.save());
+        }
+        fs::write(&save_path, bytes).map_err(|e| e.to_string())?;
+        self.is_ram_dirty = false;
+        Ok(())
+    }
+
+    /// Only saves if the RAM changed since the last save
+    pub fn flush_save(&mut self) -> Result<(), String> {
+        if self.is_ram_dirty {
+            self.save()
         } else {
-            eprintln!("Reading outside of cartridge at {}!", index);
-            0
+            Ok(())
         }
     }
 
-    pub fn write(&mut self, address: u16, data: u8) {
-        eprintln!("Writting to cartridge at {:x} {:x}", address, data);
+    pub fn tick(&mut self) {
+        self.mbc.tick();
+    }
+
+    pub fn rtc(&mut self) -> Option<&mut Rtc> {
+        match &mut self.mbc {
+            MemoryBankController::Mbc3(mbc) => mbc.rtc.as_mut(),
+            _ => None,
+        }
+    }
+
+    pub fn set_rtc_clock(&mut self, clock: RtcClock) {
+        if let Some(rtc) = self.rtc() {
+            rtc.set_clock(clock);
+        }
     }
 
     pub fn print_info(&self) {
@@ -90,3 +223,74 @@ impl Cartridge {
         eprintln!("8 KiB RAM banks: {:02X}", self.ram_banks);
     }
 }
+
+// The ROM itself is not saved, only its header checksums to recognize it
+impl Snapshot for Cartridge {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.data[0x014D..=0x014F]);
+        self.mbc.save_state(state);
+        state.bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        if state.bytes()? != self.data[0x014D..=0x014F] {
+            return Err("The save state is for another cartridge".to_string());
+        }
+        self.mbc.load_state(state)?;
+        state.bytes_into(&mut self.ram)?;
+        self.is_ram_dirty = true;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom(cartridge_type: u8, ram_size: u8) -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0147] = cartridge_type;
+        rom[0x0149] = ram_size;
+        rom
+    }
+
+    #[test]
+    fn battery_ram_is_saved_and_loaded() {
+        let directory = std::env::temp_dir().join(format!("gb-save-test-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let rom_path = directory.join("game.gb");
+        // MBC3+TIMER+RAM+BATTERY with 32 KiB RAM
+        fs::write(&rom_path, rom(0x10, 3)).unwrap();
+
+        let mut cartridge = Cartridge::load_from_file(rom_path.to_str().unwrap()).unwrap();
+        cartridge.set_rtc_clock(RtcClock::Emulated);
+        cartridge.write(0x0000, 0x0A);
+        cartridge.write(0x4000, 0x02);
+        cartridge.write_ram(0xA123, 0x42);
+        cartridge.write(0x4000, 0x09);
+        cartridge.write_ram(0xA000, 17);
+        cartridge.save().unwrap();
+
+        let save = fs::read(directory.join("game.sav")).unwrap();
+        assert_eq!(save.len(), 4 * RAM_BANK_SIZE + RTC_SAVE_SIZE);
+        assert_eq!(save[2 * RAM_BANK_SIZE + 0x123], 0x42);
+
+        let mut loaded = Cartridge::load_from_file(rom_path.to_str().unwrap()).unwrap();
+        loaded.write(0x0000, 0x0A);
+        loaded.write(0x4000, 0x02);
+        assert_eq!(loaded.read_ram(0xA123), 0x42);
+        loaded.write(0x4000, 0x09);
+        assert!(loaded.read_ram(0xA000) >= 17);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn no_save_without_battery() {
+        let mut cartridge = Cartridge::from_bytes(rom(0x02, 2)).unwrap();
+        cartridge.write(0x0000, 0x0A);
+        cartridge.write_ram(0xA000, 0x42);
+        assert!(cartridge.save_path.is_none());
+        assert!(cartridge.flush_save().is_ok());
+    }
+}
@@ -1,3 +1,4 @@
+use crate::util::state::{Snapshot, StateReader, StateWriter};
 use crate::{memory::bus::Bus, util::helper::combine_to_u16};
 
 /// Direct Memory Access
@@ -55,3 +56,182 @@ impl Dma {
         }
     }
 }
+
+/// CGB VRAM DMA (HDMA1-HDMA5)
+/// Copies blocks of 16 bytes into VRAM, either all at once (general purpose DMA)
+/// or one block at the start of every HBlank. The CPU is stalled while a block is copied.
+#[derive(Debug)]
+pub struct Hdma {
+    source: u16,      // HDMA1 and HDMA2, the lower 4 bits are ignored
+    destination: u16, // HDMA3 and HDMA4, an offset into VRAM
+    length: u8,       // blocks left minus one, reads back through HDMA5
+    is_active: bool,
+    is_hblank: bool,
+    is_block_due: bool, // an HBlank started and its block is not copied yet
+}
+
+impl Hdma {
+    pub fn new() -> Self {
+        Hdma {
+            source: 0,
+            destination: 0,
+            length: 0x7F,
+            is_active: false,
+            is_hblank: false,
+            is_block_due: false,
+        }
+    }
+
+    pub fn write(&mut self, offset: u8, data: u8) {
+        match offset {
+            0x51 => self.source = (self.source & 0x00FF) | (data as u16) << 8,
+            0x52 => self.source = (self.source & 0xFF00) | (data & 0xF0) as u16,
+            0x53 => self.destination = (self.destination & 0x00FF) | ((data & 0x1F) as u16) << 8,
+            0x54 => self.destination = (self.destination & 0xFF00) | (data & 0xF0) as u16,
+            0x55 => self.write_control(data),
+            _ => unreachable!(),
+        }
+    }
+
+    /// HDMA5: bit 7 is clear while a transfer is active, bits 0-6 are the blocks left minus one
+    pub fn read_control(&self) -> u8 {
+        (!self.is_active as u8) << 7 | self.length
+    }
+
+    fn write_control(&mut self, data: u8) {
+        if self.is_active && self.is_hblank && data & 0x80 == 0 {
+            // cancels the HBlank DMA, the length left can still be read back
+            self.is_active = false;
+            self.is_block_due = false;
+            return;
+        }
+        self.length = data & 0x7F;
+        self.is_active = true;
+        self.is_hblank = data & 0x80 != 0;
+        self.is_block_due = false;
+    }
+
+    /// Called by the PPU when it enters HBlank
+    pub fn start_hblank_block(&mut self) {
+        if self.is_active && self.is_hblank {
+            self.is_block_due = true;
+        }
+    }
+
+    /// Whether the CPU has to wait for a block to be copied
+    pub fn is_copying(&self) -> bool {
+        self.is_active && (!self.is_hblank || self.is_block_due)
+    }
+
+    /// Copies the next 16 bytes
+    pub fn copy_block(bus: &mut Bus) {
+        for _ in 0..16 {
+            let data = bus.read(bus.hdma.source);
+            bus.write(0x8000 | bus.hdma.destination, data);
+            bus.hdma.source = bus.hdma.source.wrapping_add(1);
+            bus.hdma.destination = (bus.hdma.destination + 1) & 0x1FFF;
+        }
+        bus.hdma.is_block_due = false;
+        bus.hdma.length = bus.hdma.length.wrapping_sub(1) & 0x7F;
+        // the transfer also ends when the destination wraps past 0x9FFF
+        if bus.hdma.length == 0x7F || bus.hdma.destination == 0 {
+            bus.hdma.is_active = false;
+        }
+    }
+}
+
+impl Snapshot for Dma {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.is_active);
+        state.u8(self.upper);
+        state.u8(self.lower);
+        state.u8(self.start_delay);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.is_active = state.bool()?;
+        self.upper = state.u8()?;
+        self.lower = state.u8()?;
+        self.start_delay = state.u8()?;
+        Ok(())
+    }
+}
+
+impl Snapshot for Hdma {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u16(self.source);
+        state.u16(self.destination);
+        state.u8(self.length);
+        state.bool(self.is_active);
+        state.bool(self.is_hblank);
+        state.bool(self.is_block_due);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.source = state.u16()?;
+        self.destination = state.u16()? & 0x1FF0;
+        self.length = state.u8()? & 0x7F;
+        self.is_active = state.bool()?;
+        self.is_hblank = state.bool()?;
+        self.is_block_due = state.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::cartridge::Cartridge;
+    use crate::model::Model;
+
+    fn cgb_bus() -> Bus {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0143] = 0x80;
+        let mut bus = Bus::new(Cartridge::from_bytes(rom).unwrap());
+        bus.set_model(Model::Cgb);
+        for i in 0..0x40 {
+            bus.write(0xC000 + i, i as u8 + 1);
+        }
+        // from 0xC000 to 0x8100 in VRAM bank 1
+        bus.write(0xFF4F, 1);
+        for (offset, data) in [(0x51, 0xC0), (0x52, 0x00), (0x53, 0x81), (0x54, 0x00)] {
+            bus.write(0xFF00 | offset, data);
+        }
+        bus
+    }
+
+    #[test]
+    fn general_purpose_dma() {
+        let mut bus = cgb_bus();
+        bus.write(0xFF55, 0x01);
+        assert_eq!(bus.read(0xFF55), 0x01);
+        while bus.hdma.is_copying() {
+            Hdma::copy_block(&mut bus);
+        }
+        assert_eq!(bus.read(0xFF55), 0xFF);
+        assert_eq!(bus.read_vram(1, 0x8100), 0x01);
+        assert_eq!(bus.read_vram(1, 0x811F), 0x20);
+        assert_eq!(bus.read_vram(1, 0x8120), 0x00);
+    }
+
+    #[test]
+    fn hblank_dma_and_cancellation() {
+        let mut bus = cgb_bus();
+        bus.write(0xFF55, 0x82);
+        assert!(!bus.hdma.is_copying());
+
+        bus.hdma.start_hblank_block();
+        assert!(bus.hdma.is_copying());
+        Hdma::copy_block(&mut bus);
+        assert!(!bus.hdma.is_copying());
+        assert_eq!(bus.read(0xFF55), 0x01);
+        assert_eq!(bus.read_vram(1, 0x810F), 0x10);
+        assert_eq!(bus.read_vram(1, 0x8110), 0x00);
+
+        // the remaining length is kept with bit 7 set
+        bus.write(0xFF55, 0x00);
+        assert_eq!(bus.read(0xFF55), 0x81);
+        bus.hdma.start_hblank_block();
+        assert!(!bus.hdma.is_copying());
+    }
+}
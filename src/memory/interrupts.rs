@@ -1,3 +1,5 @@
+use crate::util::state::{Snapshot, StateReader, StateWriter};
+
 #[derive(Clone, Debug)]
 pub enum Interrupt {
     VBlank,
@@ -101,3 +103,18 @@ impl InterruptHandler {
         self.requested |= interrupt.bit();
     }
 }
+
+impl Snapshot for InterruptHandler {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.master_enabled);
+        state.u8(self.enabled);
+        state.u8(self.requested);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.master_enabled = state.bool()?;
+        self.enabled = state.u8()?;
+        self.requested = state.u8()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,115 @@
+use crate::input::{Button, Input};
+use crate::util::helper::is_bit_set;
+use crate::util::state::{Snapshot, StateReader, StateWriter};
+
+/// P1/JOYP: the buttons are read through a 2x4 matrix,
+/// bit 4 (P14) low selects the d-pad, bit 5 (P15) low selects the buttons.
+/// The lower nibble is active-low.
+#[derive(Debug)]
+pub struct Joypad {
+    select: u8, // bits 4 and 5 of P1
+    input: Input,
+}
+
+impl Joypad {
+    pub fn new() -> Self {
+        Joypad {
+            select: 0b0011_0000,
+            input: Input::new(),
+        }
+    }
+
+    fn pressed_lines(&self) -> u8 {
+        let mut lines = 0;
+        if !is_bit_set(self.select, 4) {
+            lines |= Self::lines(
+                &self.input,
+                [Button::Right, Button::Left, Button::Up, Button::Down],
+            );
+        }
+        if !is_bit_set(self.select, 5) {
+            lines |= Self::lines(
+                &self.input,
+                [Button::A, Button::B, Button::Select, Button::Start],
+            );
+        }
+        lines
+    }
+
+    fn lines(input: &Input, buttons: [Button; 4]) -> u8 {
+        buttons
+            .iter()
+            .enumerate()
+            .filter(|(_, button)| input.is_pressed(**button))
+            .fold(0, |lines, (bit, _)| lines | 1 << bit)
+    }
+
+    pub fn read(&self) -> u8 {
+        // the upper two bits are unused and read as 1
+        0b1100_0000 | self.select | (!self.pressed_lines() & 0x0F)
+    }
+
+    /// Returns whether the joypad interrupt should be requested
+    pub fn write(&mut self, data: u8) -> bool {
+        let before = self.pressed_lines();
+        self.select = data & 0b0011_0000;
+        Self::is_falling_edge(before, self.pressed_lines())
+    }
+
+    pub fn is_pressed(&self, button: Button) -> bool {
+        self.input.is_pressed(button)
+    }
+
+    /// Returns whether the joypad interrupt should be requested
+    pub fn set_pressed(&mut self, button: Button, pressed: bool) -> bool {
+        let before = self.pressed_lines();
+        self.input.set_pressed(button, pressed);
+        Self::is_falling_edge(before, self.pressed_lines())
+    }
+
+    // A line going from high to low is a newly pressed line
+    fn is_falling_edge(before: u8, after: u8) -> bool {
+        after & !before != 0
+    }
+}
+
+// The pressed buttons belong to the host and are not part of the state
+impl Snapshot for Joypad {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.select);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.select = state.u8()? & 0b0011_0000;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn select_lines() {
+        let mut joypad = Joypad::new();
+        joypad.set_pressed(Button::Start, true);
+        joypad.set_pressed(Button::Left, true);
+        assert_eq!(joypad.read(), 0xFF);
+
+        joypad.write(0b0010_0000);
+        assert_eq!(joypad.read(), 0b1110_1101);
+
+        joypad.write(0b0001_0000);
+        assert_eq!(joypad.read(), 0b1101_0111);
+    }
+
+    #[test]
+    fn interrupt_on_press_of_selected_line() {
+        let mut joypad = Joypad::new();
+        assert!(!joypad.set_pressed(Button::A, true));
+        assert!(joypad.write(0b0001_0000));
+        assert!(joypad.set_pressed(Button::B, true));
+        assert!(!joypad.set_pressed(Button::Down, true));
+        assert!(!joypad.set_pressed(Button::A, false));
+    }
+}
@@ -1,5 +1,8 @@
+use super::palette::ColorPalettes;
 use crate::util::helper::{is_bit_set, set_bit};
+use crate::util::state::{Snapshot, StateReader, StateWriter};
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum LcdMode {
     HBlank,
     VBlank,
@@ -14,8 +17,11 @@ pub enum Palette {
     Obj1,
 }
 
+// STAT bit 6: request an interrupt when LY == LYC
+const LYC_INTERRUPT_SOURCE: u8 = 1 << 6;
+
 impl LcdMode {
-    pub fn toStatusBit(&self) -> u8 {
+    pub fn to_status_bit(self) -> u8 {
         match self {
             Self::HBlank => 0b00,
             Self::VBlank => 0b01,
@@ -24,12 +30,13 @@ impl LcdMode {
         }
     }
 
-    pub fn getInterruptSourceBit(&self) -> u8 {
+    // There is no STAT interrupt source for the pixel transfer
+    pub fn interrupt_source_bit(self) -> Option<u8> {
         match self {
-            Self::HBlank => 1 << 3,
-            Self::VBlank => 1 << 4,
-            Self::SearchingOam => 1 << 5,
-            Self::TransferingData => 1 << 6,
+            Self::HBlank => Some(1 << 3),
+            Self::VBlank => Some(1 << 4),
+            Self::SearchingOam => Some(1 << 5),
+            Self::TransferingData => None,
         }
     }
 }
@@ -47,6 +54,11 @@ pub struct Lcd {
     pub obj_palette_1: u8,
     pub win_y: u8,
     pub win_x: u8,
+
+    // CGB
+    pub bg_colors: ColorPalettes,
+    pub obj_colors: ColorPalettes,
+    pub object_priority: u8, // OPRI: 0 by OAM position, 1 by X coordinate like the DMG
 }
 
 impl Lcd {
@@ -63,6 +75,10 @@ impl Lcd {
             obj_palette_1: 0xFF,
             win_y: 0,
             win_x: 0,
+
+            bg_colors: ColorPalettes::new(),
+            obj_colors: ColorPalettes::new(),
+            object_priority: 0,
         }
     }
 
@@ -126,7 +142,7 @@ impl Lcd {
 
     pub fn set_mode(&mut self, mode: LcdMode) {
         self.status &= 0b1111_1100;
-        self.status |= mode.toStatusBit();
+        self.status |= mode.to_status_bit();
     }
 
     pub fn lyc(&self) -> bool {
@@ -137,8 +153,28 @@ impl Lcd {
         self.status = set_bit(self.status, 2, value)
     }
 
-    pub fn status_interrupt_int(&self, source: u8) {
-        self.status & source;
+    pub fn status_interrupt_int(&self, source: u8) -> bool {
+        self.status & source != 0
+    }
+
+    /// Whether any enabled STAT interrupt source is currently active
+    pub fn stat_interrupt_line(&self) -> bool {
+        let mode_source = match self.mode().interrupt_source_bit() {
+            Some(source) => self.status_interrupt_int(source),
+            None => false,
+        };
+        let lyc_source = self.lyc() && self.status_interrupt_int(LYC_INTERRUPT_SOURCE);
+        mode_source || lyc_source
+    }
+
+    pub fn read_status(&self) -> u8 {
+        // bit 7 is unused and always reads as 1
+        self.status | 0b1000_0000
+    }
+
+    pub fn write_status(&mut self, data: u8) {
+        // mode and LYC flag are read-only
+        self.status = (data & 0b0111_1000) | (self.status & 0b0000_0111);
     }
 
     pub fn update_palette(&mut self, value: u8, palette: Palette) {
@@ -151,3 +187,47 @@ impl Lcd {
         }
     }
 }
+
+impl Snapshot for Lcd {
+    fn save_state(&self, state: &mut StateWriter) {
+        for register in [
+            self.control,
+            self.status,
+            self.scroll_y,
+            self.scroll_x,
+            self.ly,
+            self.ly_compare,
+            self.bg_palette,
+            self.obj_palette_0,
+            self.obj_palette_1,
+            self.win_y,
+            self.win_x,
+            self.object_priority,
+        ] {
+            state.u8(register);
+        }
+        self.bg_colors.save_state(state);
+        self.obj_colors.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        for register in [
+            &mut self.control,
+            &mut self.status,
+            &mut self.scroll_y,
+            &mut self.scroll_x,
+            &mut self.ly,
+            &mut self.ly_compare,
+            &mut self.bg_palette,
+            &mut self.obj_palette_0,
+            &mut self.obj_palette_1,
+            &mut self.win_y,
+            &mut self.win_x,
+            &mut self.object_priority,
+        ] {
+            *register = state.u8()?;
+        }
+        self.bg_colors.load_state(state)?;
+        self.obj_colors.load_state(state)
+    }
+}
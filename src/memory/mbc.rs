@@ -1,2 +0,0 @@
-/// Memory bank controller
-
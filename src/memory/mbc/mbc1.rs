@@ -0,0 +1,177 @@
+use crate::util::state::{Snapshot, StateReader, StateWriter};
+
+#[derive(Debug, PartialEq)]
+pub enum BankingMode {
+    Rom, // mode 0: BANK2 only applies to 0x4000-0x7FFF
+    Ram, // mode 1: BANK2 also applies to 0x0000-0x3FFF and the RAM
+}
+
+/// MBC1, up to 2 MiB ROM and 32 KiB RAM
+#[derive(Debug)]
+pub struct Mbc1 {
+    is_ram_enabled: bool,
+    bank1: u8, // 5-bit lower ROM bank number
+    bank2: u8, // 2-bit upper ROM bank number or RAM bank number
+    banking_mode: BankingMode,
+    // MBC1M multicarts only connect four bits of BANK1
+    is_multicart: bool,
+}
+
+impl Mbc1 {
+    pub fn new(is_multicart: bool) -> Self {
+        Mbc1 {
+            is_ram_enabled: false,
+            bank1: 1,
+            bank2: 0,
+            banking_mode: BankingMode::Rom,
+            is_multicart,
+        }
+    }
+
+    fn bank2_shift(&self) -> u8 {
+        if self.is_multicart {
+            4
+        } else {
+            5
+        }
+    }
+
+    pub fn is_ram_enabled(&self) -> bool {
+        self.is_ram_enabled
+    }
+
+    pub fn low_rom_bank(&self) -> usize {
+        match self.banking_mode {
+            BankingMode::Rom => 0,
+            BankingMode::Ram => (self.bank2 << self.bank2_shift()) as usize,
+        }
+    }
+
+    pub fn high_rom_bank(&self) -> usize {
+        let bank1_mask = (1 << self.bank2_shift()) - 1;
+        ((self.bank2 << self.bank2_shift()) | (self.bank1 & bank1_mask)) as usize
+    }
+
+    pub fn ram_bank(&self) -> usize {
+        match self.banking_mode {
+            BankingMode::Rom => 0,
+            BankingMode::Ram => self.bank2 as usize,
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, data: u8) {
+        match address {
+            0x0000..=0x1FFF => self.is_ram_enabled = data & 0x0F == 0x0A,
+            0x2000..=0x3FFF => {
+                // Selecting bank 0 selects bank 1 instead, this is checked
+                // on all five bits, so 0x20, 0x40 and 0x60 can't be mapped here
+                self.bank1 = data & 0b1_1111;
+                if self.bank1 == 0 {
+                    self.bank1 = 1;
+                }
+            }
+            0x4000..=0x5FFF => self.bank2 = data & 0b11,
+            0x6000..=0x7FFF => {
+                self.banking_mode = if data & 1 == 0 {
+                    BankingMode::Rom
+                } else {
+                    BankingMode::Ram
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl Snapshot for Mbc1 {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.is_ram_enabled);
+        state.u8(self.bank1);
+        state.u8(self.bank2);
+        state.bool(self.banking_mode == BankingMode::Ram);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.is_ram_enabled = state.bool()?;
+        self.bank1 = state.u8()? & 0x1F;
+        self.bank2 = state.u8()? & 0b11;
+        self.banking_mode = if state.bool()? {
+            BankingMode::Ram
+        } else {
+            BankingMode::Rom
+        };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{MemoryBankController, ROM_BANK_SIZE};
+    use super::*;
+
+    // Every bank starts with its own bank number
+    fn rom(banks: usize) -> Vec<u8> {
+        let mut rom = vec![0; banks * ROM_BANK_SIZE];
+        for bank in 0..banks {
+            rom[bank * ROM_BANK_SIZE] = bank as u8;
+        }
+        rom
+    }
+
+    #[test]
+    fn bank_zero_selects_bank_one() {
+        let rom = rom(128);
+        let mut mbc = MemoryBankController::Mbc1(Mbc1::new(false));
+        mbc.write_register(0x2000, 0);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 1);
+        mbc.write_register(0x2000, 0x05);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 5);
+        // only the lower 5 bits are checked for 0
+        mbc.write_register(0x2000, 0x20);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 1);
+    }
+
+    #[test]
+    fn large_rom_upper_bits() {
+        let rom = rom(128);
+        let mut mbc = MemoryBankController::Mbc1(Mbc1::new(false));
+        mbc.write_register(0x4000, 0b10);
+        mbc.write_register(0x2000, 0x03);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 0x43);
+        assert_eq!(mbc.read_rom(&rom, 0x0000), 0);
+
+        mbc.write_register(0x6000, 1);
+        assert_eq!(mbc.read_rom(&rom, 0x0000), 0x40);
+    }
+
+    #[test]
+    fn multicart_banks() {
+        let rom = rom(64);
+        let mut mbc = MemoryBankController::Mbc1(Mbc1::new(true));
+        mbc.write_register(0x4000, 0b01);
+        mbc.write_register(0x2000, 0x12);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 0x12);
+        mbc.write_register(0x6000, 1);
+        assert_eq!(mbc.read_rom(&rom, 0x0000), 0x10);
+    }
+
+    #[test]
+    fn ram_enable_and_banks() {
+        let mut ram = vec![0; 4 * 0x2000];
+        let mut mbc = MemoryBankController::Mbc1(Mbc1::new(false));
+        mbc.write_ram(&mut ram, 0xA000, 0x12);
+        assert_eq!(mbc.read_ram(&ram, 0xA000), 0xFF);
+
+        mbc.write_register(0x0000, 0x0A);
+        mbc.write_ram(&mut ram, 0xA000, 0x12);
+        assert_eq!(mbc.read_ram(&ram, 0xA000), 0x12);
+
+        // the RAM bank is only switched in mode 1
+        mbc.write_register(0x4000, 2);
+        assert_eq!(mbc.read_ram(&ram, 0xA000), 0x12);
+        mbc.write_register(0x6000, 1);
+        mbc.write_ram(&mut ram, 0xA000, 0x34);
+        assert_eq!(ram[2 * 0x2000], 0x34);
+        assert_eq!(mbc.read_ram(&ram, 0xA000), 0x34);
+    }
+}
@@ -0,0 +1,108 @@
+use crate::util::state::{Snapshot, StateReader, StateWriter};
+
+// 512 half-bytes of RAM are built into the MBC2 itself
+pub const MBC2_RAM_SIZE: usize = 512;
+
+/// MBC2, up to 256 KiB ROM and 512x4 bits of built-in RAM
+#[derive(Debug)]
+pub struct Mbc2 {
+    is_ram_enabled: bool,
+    rom_bank: u8, // 4-bit ROM bank number
+}
+
+impl Mbc2 {
+    pub fn new() -> Self {
+        Mbc2 {
+            is_ram_enabled: false,
+            rom_bank: 1,
+        }
+    }
+
+    pub fn high_rom_bank(&self) -> usize {
+        self.rom_bank as usize
+    }
+
+    pub fn write_register(&mut self, address: u16, data: u8) {
+        // Only 0x0000-0x3FFF is used, bit 8 of the address selects the register
+        if address >= 0x4000 {
+            return;
+        }
+        if address & 0x0100 == 0 {
+            self.is_ram_enabled = data & 0x0F == 0x0A;
+        } else {
+            self.rom_bank = data & 0x0F;
+            if self.rom_bank == 0 {
+                self.rom_bank = 1;
+            }
+        }
+    }
+
+    // The RAM echoes across 0xA000-0xBFFF and the upper nibble reads as 1s
+    pub fn read_ram(&self, ram: &[u8], offset: usize) -> u8 {
+        if !self.is_ram_enabled || ram.is_empty() {
+            return 0xFF;
+        }
+        0xF0 | ram[offset % MBC2_RAM_SIZE]
+    }
+
+    pub fn write_ram(&mut self, ram: &mut [u8], offset: usize, data: u8) {
+        if !self.is_ram_enabled || ram.is_empty() {
+            return;
+        }
+        ram[offset % MBC2_RAM_SIZE] = data & 0x0F;
+    }
+}
+
+impl Snapshot for Mbc2 {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.is_ram_enabled);
+        state.u8(self.rom_bank);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.is_ram_enabled = state.bool()?;
+        self.rom_bank = state.u8()? & 0x0F;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{MemoryBankController, ROM_BANK_SIZE};
+    use super::*;
+
+    #[test]
+    fn register_selected_by_address_bit_8() {
+        let mut rom = vec![0; 16 * ROM_BANK_SIZE];
+        rom[0x0F * ROM_BANK_SIZE] = 0x0F;
+        rom[ROM_BANK_SIZE] = 0x01;
+        let mut ram = vec![0; MBC2_RAM_SIZE];
+        let mut mbc = MemoryBankController::Mbc2(Mbc2::new());
+
+        mbc.write_register(0x2100, 0xFF);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 0x0F);
+        mbc.write_register(0x0100, 0x00);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 0x01);
+
+        // bit 8 clear: RAM enable, the ROM bank stays unchanged
+        mbc.write_register(0x2000, 0x0A);
+        mbc.write_ram(&mut ram, 0xA000, 0x00);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 0x01);
+        assert_eq!(mbc.read_ram(&ram, 0xA000), 0xF0);
+    }
+
+    #[test]
+    fn half_byte_ram_echo() {
+        let mut ram = vec![0; MBC2_RAM_SIZE];
+        let mut mbc = MemoryBankController::Mbc2(Mbc2::new());
+        mbc.write_register(0x0000, 0x0A);
+        mbc.write_ram(&mut ram, 0xA001, 0xAB);
+        assert_eq!(ram[1], 0x0B);
+        assert_eq!(mbc.read_ram(&ram, 0xA001), 0xFB);
+        assert_eq!(mbc.read_ram(&ram, 0xA201), 0xFB);
+        assert_eq!(mbc.read_ram(&ram, 0xBE01), 0xFB);
+
+        mbc.write_register(0x0000, 0x00);
+        assert_eq!(mbc.read_ram(&ram, 0xA001), 0xFF);
+    }
+}
@@ -0,0 +1,163 @@
+use super::rtc::{Rtc, RtcClock};
+use crate::util::state::{Snapshot, StateReader, StateWriter};
+
+/// MBC3, up to 2 MiB ROM, 32 KiB RAM and an optional real time clock
+#[derive(Debug)]
+pub struct Mbc3 {
+    is_ram_enabled: bool, // also enables the RTC registers
+    rom_bank: u8,         // 7-bit ROM bank number
+    ram_select: u8,       // RAM bank 0x00-0x03 or RTC register 0x08-0x0C
+    latch_written: Option<u8>,
+    pub rtc: Option<Rtc>,
+}
+
+impl Mbc3 {
+    pub fn new(has_rtc: bool) -> Self {
+        Mbc3 {
+            is_ram_enabled: false,
+            rom_bank: 1,
+            ram_select: 0,
+            latch_written: None,
+            rtc: has_rtc.then(|| Rtc::new(RtcClock::WallTime)),
+        }
+    }
+
+    pub fn high_rom_bank(&self) -> usize {
+        self.rom_bank as usize
+    }
+
+    pub fn tick(&mut self) {
+        if let Some(rtc) = &mut self.rtc {
+            rtc.tick();
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, data: u8) {
+        match address {
+            0x0000..=0x1FFF => self.is_ram_enabled = data & 0x0F == 0x0A,
+            0x2000..=0x3FFF => {
+                // unlike MBC1 bank 0 can't be selected
+                self.rom_bank = data & 0x7F;
+                if self.rom_bank == 0 {
+                    self.rom_bank = 1;
+                }
+            }
+            0x4000..=0x5FFF => self.ram_select = data,
+            0x6000..=0x7FFF => {
+                // writing 0x00 then 0x01 latches the clock
+                if self.latch_written == Some(0) && data == 1 {
+                    if let Some(rtc) = &mut self.rtc {
+                        rtc.latch();
+                    }
+                }
+                self.latch_written = Some(data);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn selected_rtc(&self) -> Option<&Rtc> {
+        match self.ram_select {
+            0x08..=0x0C => self.rtc.as_ref(),
+            _ => None,
+        }
+    }
+
+    pub fn read_ram(&self, ram: &[u8], offset: usize) -> u8 {
+        if !self.is_ram_enabled {
+            return 0xFF;
+        }
+        match self.ram_select {
+            0x00..=0x07 => {
+                super::read_banked(ram, self.ram_select as usize, super::RAM_BANK_SIZE, offset)
+            }
+            _ => self
+                .selected_rtc()
+                .map_or(0xFF, |rtc| rtc.read(self.ram_select)),
+        }
+    }
+
+    pub fn write_ram(&mut self, ram: &mut [u8], offset: usize, data: u8) {
+        if !self.is_ram_enabled {
+            return;
+        }
+        match self.ram_select {
+            0x00..=0x07 => super::write_banked(
+                ram,
+                self.ram_select as usize,
+                super::RAM_BANK_SIZE,
+                offset,
+                data,
+            ),
+            0x08..=0x0C => {
+                if let Some(rtc) = &mut self.rtc {
+                    rtc.write(self.ram_select, data);
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+impl Snapshot for Mbc3 {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.is_ram_enabled);
+        state.u8(self.rom_bank);
+        state.u8(self.ram_select);
+        state.bool(self.latch_written.is_some());
+        state.u8(self.latch_written.unwrap_or(0));
+        if let Some(rtc) = &self.rtc {
+            rtc.save_state(state);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.is_ram_enabled = state.bool()?;
+        self.rom_bank = state.u8()? & 0x7F;
+        self.ram_select = state.u8()?;
+        let has_latch_write = state.bool()?;
+        let latch_written = state.u8()?;
+        self.latch_written = has_latch_write.then_some(latch_written);
+        if let Some(rtc) = &mut self.rtc {
+            rtc.load_state(state)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::MemoryBankController;
+    use super::*;
+
+    #[test]
+    fn rom_banks_and_rtc_registers() {
+        let mut rom = vec![0; 128 * 0x4000];
+        rom[0x7F * 0x4000] = 0x7F;
+        rom[0x4000] = 0x01;
+        let mut ram = vec![0; 4 * 0x2000];
+        let mut mbc = MemoryBankController::Mbc3(Mbc3::new(true));
+        if let MemoryBankController::Mbc3(mbc3) = &mut mbc {
+            mbc3.rtc.as_mut().unwrap().set_clock(RtcClock::Emulated);
+        }
+
+        mbc.write_register(0x2000, 0xFF);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 0x7F);
+        mbc.write_register(0x2000, 0x00);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 0x01);
+
+        mbc.write_register(0x0000, 0x0A);
+        mbc.write_register(0x4000, 0x03);
+        mbc.write_ram(&mut ram, 0xA000, 0x33);
+        assert_eq!(ram[3 * 0x2000], 0x33);
+
+        mbc.write_register(0x4000, 0x09);
+        mbc.write_ram(&mut ram, 0xA000, 30);
+        assert_eq!(mbc.read_ram(&ram, 0xA000), 30);
+        mbc.write_register(0x6000, 0x00);
+        mbc.write_register(0x6000, 0x01);
+        assert_eq!(mbc.read_ram(&ram, 0xA000), 30);
+        mbc.write_register(0x4000, 0x03);
+        assert_eq!(mbc.read_ram(&ram, 0xA000), 0x33);
+    }
+}
@@ -0,0 +1,113 @@
+use crate::util::state::{Snapshot, StateReader, StateWriter};
+
+/// MBC5, up to 8 MiB ROM and 128 KiB RAM
+#[derive(Debug)]
+pub struct Mbc5 {
+    is_ram_enabled: bool,
+    rom_bank: u16, // 9-bit ROM bank number, bank 0 can be selected
+    ram_bank: u8,
+    // Rumble carts wire bit 3 of the RAM bank register to the motor
+    has_rumble: bool,
+    is_rumbling: bool,
+}
+
+impl Mbc5 {
+    pub fn new(has_rumble: bool) -> Self {
+        Mbc5 {
+            is_ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            has_rumble,
+            is_rumbling: false,
+        }
+    }
+
+    pub fn high_rom_bank(&self) -> usize {
+        self.rom_bank as usize
+    }
+
+    pub fn ram_bank(&self) -> usize {
+        self.ram_bank as usize
+    }
+
+    pub fn is_ram_enabled(&self) -> bool {
+        self.is_ram_enabled
+    }
+
+    pub fn is_rumbling(&self) -> bool {
+        self.is_rumbling
+    }
+
+    pub fn write_register(&mut self, address: u16, data: u8) {
+        match address {
+            0x0000..=0x1FFF => self.is_ram_enabled = data == 0x0A,
+            0x2000..=0x2FFF => self.rom_bank = (self.rom_bank & 0x100) | data as u16,
+            0x3000..=0x3FFF => self.rom_bank = (self.rom_bank & 0xFF) | ((data as u16 & 1) << 8),
+            0x4000..=0x5FFF => {
+                if self.has_rumble {
+                    self.is_rumbling = data & 0b1000 != 0;
+                    self.ram_bank = data & 0b0111;
+                } else {
+                    self.ram_bank = data & 0b1111;
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+impl Snapshot for Mbc5 {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.is_ram_enabled);
+        state.u16(self.rom_bank);
+        state.u8(self.ram_bank);
+        state.bool(self.is_rumbling);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.is_ram_enabled = state.bool()?;
+        self.rom_bank = state.u16()? & 0x1FF;
+        self.ram_bank = state.u8()? & 0x0F;
+        self.is_rumbling = state.bool()? && self.has_rumble;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{MemoryBankController, ROM_BANK_SIZE};
+    use super::*;
+
+    #[test]
+    fn nine_bit_rom_bank() {
+        let mut rom = vec![0; 512 * ROM_BANK_SIZE];
+        rom[0x1FF * ROM_BANK_SIZE] = 0xAA;
+        rom[0x100 * ROM_BANK_SIZE] = 0xBB;
+        let mut mbc = MemoryBankController::Mbc5(Mbc5::new(false));
+
+        mbc.write_register(0x2000, 0xFF);
+        mbc.write_register(0x3000, 0x01);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 0xAA);
+        // bank 0 can be mapped to 0x4000-0x7FFF
+        mbc.write_register(0x2000, 0x00);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 0xBB);
+        mbc.write_register(0x3000, 0x00);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), mbc.read_rom(&rom, 0x0000));
+    }
+
+    #[test]
+    fn rumble_uses_ram_bank_bit_3() {
+        let mut ram = vec![0; 16 * 0x2000];
+        let mut mbc = Mbc5::new(true);
+        mbc.write_register(0x4000, 0b1010);
+        assert!(mbc.is_rumbling());
+        assert_eq!(mbc.ram_bank(), 2);
+
+        let mut mbc = MemoryBankController::Mbc5(Mbc5::new(false));
+        mbc.write_register(0x0000, 0x0A);
+        mbc.write_register(0x4000, 0x0F);
+        mbc.write_ram(&mut ram, 0xA000, 0x42);
+        assert_eq!(ram[15 * 0x2000], 0x42);
+        assert!(!mbc.is_rumbling());
+    }
+}
@@ -0,0 +1,169 @@
+pub mod mbc1;
+pub mod mbc2;
+pub mod mbc3;
+pub mod mbc5;
+pub mod rtc;
+
+use crate::util::state::{Snapshot, StateReader, StateWriter};
+use mbc1::Mbc1;
+use mbc2::Mbc2;
+use mbc3::Mbc3;
+use mbc5::Mbc5;
+
+pub const ROM_BANK_SIZE: usize = 0x4000; // 16 KiB
+pub const RAM_BANK_SIZE: usize = 0x2000; // 8 KiB
+
+/// Memory bank controller
+#[derive(Debug)]
+pub enum MemoryBankController {
+    RomOnly,
+    Mbc1(Mbc1),
+    Mbc2(Mbc2),
+    Mbc3(Mbc3),
+    Mbc5(Mbc5),
+}
+
+// Banks past the end of the ROM or RAM wrap around,
+// as only as many address lines as needed are connected
+fn read_banked(data: &[u8], bank: usize, bank_size: usize, offset: usize) -> u8 {
+    if data.is_empty() {
+        return 0xFF;
+    }
+    data[(bank * bank_size + offset) % data.len()]
+}
+
+fn write_banked(data: &mut [u8], bank: usize, bank_size: usize, offset: usize, value: u8) {
+    if data.is_empty() {
+        return;
+    }
+    let index = (bank * bank_size + offset) % data.len();
+    data[index] = value;
+}
+
+impl MemoryBankController {
+    /// 0x0000-0x7FFF
+    pub fn read_rom(&self, rom: &[u8], address: u16) -> u8 {
+        let (bank, offset) = if address < 0x4000 {
+            (self.low_rom_bank(), address as usize)
+        } else {
+            (self.high_rom_bank(), address as usize - ROM_BANK_SIZE)
+        };
+        read_banked(rom, bank, ROM_BANK_SIZE, offset)
+    }
+
+    // The bank mapped at 0x0000-0x3FFF
+    fn low_rom_bank(&self) -> usize {
+        match self {
+            Self::RomOnly => 0,
+            Self::Mbc1(mbc) => mbc.low_rom_bank(),
+            Self::Mbc2(_) | Self::Mbc3(_) | Self::Mbc5(_) => 0,
+        }
+    }
+
+    // The bank mapped at 0x4000-0x7FFF
+    fn high_rom_bank(&self) -> usize {
+        match self {
+            Self::RomOnly => 1,
+            Self::Mbc1(mbc) => mbc.high_rom_bank(),
+            Self::Mbc2(mbc) => mbc.high_rom_bank(),
+            Self::Mbc3(mbc) => mbc.high_rom_bank(),
+            Self::Mbc5(mbc) => mbc.high_rom_bank(),
+        }
+    }
+
+    /// Writes to 0x0000-0x7FFF set the controller registers
+    pub fn write_register(&mut self, address: u16, data: u8) {
+        match self {
+            Self::RomOnly => (),
+            Self::Mbc1(mbc) => mbc.write_register(address, data),
+            Self::Mbc2(mbc) => mbc.write_register(address, data),
+            Self::Mbc3(mbc) => mbc.write_register(address, data),
+            Self::Mbc5(mbc) => mbc.write_register(address, data),
+        }
+    }
+
+    /// Advances clocks on the cartridge by one machine cycle
+    pub fn tick(&mut self) {
+        if let Self::Mbc3(mbc) = self {
+            mbc.tick();
+        }
+    }
+
+    /// 0xA000-0xBFFF, disabled RAM reads as 0xFF
+    pub fn read_ram(&self, ram: &[u8], address: u16) -> u8 {
+        let offset = (address - 0xA000) as usize;
+        match self {
+            Self::RomOnly => read_banked(ram, 0, RAM_BANK_SIZE, offset),
+            Self::Mbc1(mbc) if mbc.is_ram_enabled() => {
+                read_banked(ram, mbc.ram_bank(), RAM_BANK_SIZE, offset)
+            }
+            Self::Mbc1(_) => 0xFF,
+            Self::Mbc2(mbc) => mbc.read_ram(ram, offset),
+            Self::Mbc3(mbc) => mbc.read_ram(ram, offset),
+            Self::Mbc5(mbc) if mbc.is_ram_enabled() => {
+                read_banked(ram, mbc.ram_bank(), RAM_BANK_SIZE, offset)
+            }
+            Self::Mbc5(_) => 0xFF,
+        }
+    }
+
+    pub fn write_ram(&mut self, ram: &mut [u8], address: u16, data: u8) {
+        let offset = (address - 0xA000) as usize;
+        match self {
+            Self::RomOnly => write_banked(ram, 0, RAM_BANK_SIZE, offset, data),
+            Self::Mbc1(mbc) if mbc.is_ram_enabled() => {
+                write_banked(ram, mbc.ram_bank(), RAM_BANK_SIZE, offset, data)
+            }
+            Self::Mbc1(_) => (),
+            Self::Mbc2(mbc) => mbc.write_ram(ram, offset, data),
+            Self::Mbc3(mbc) => mbc.write_ram(ram, offset, data),
+            Self::Mbc5(mbc) if mbc.is_ram_enabled() => {
+                write_banked(ram, mbc.ram_bank(), RAM_BANK_SIZE, offset, data)
+            }
+            Self::Mbc5(_) => (),
+        }
+    }
+
+    /// Whether the rumble motor of the cartridge is on
+    pub fn is_rumbling(&self) -> bool {
+        match self {
+            Self::Mbc5(mbc) => mbc.is_rumbling(),
+            _ => false,
+        }
+    }
+}
+
+impl Snapshot for MemoryBankController {
+    fn save_state(&self, state: &mut StateWriter) {
+        match self {
+            Self::RomOnly => state.u8(0),
+            Self::Mbc1(mbc) => {
+                state.u8(1);
+                mbc.save_state(state);
+            }
+            Self::Mbc2(mbc) => {
+                state.u8(2);
+                mbc.save_state(state);
+            }
+            Self::Mbc3(mbc) => {
+                state.u8(3);
+                mbc.save_state(state);
+            }
+            Self::Mbc5(mbc) => {
+                state.u8(5);
+                mbc.save_state(state);
+            }
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        match (state.u8()?, self) {
+            (0, Self::RomOnly) => Ok(()),
+            (1, Self::Mbc1(mbc)) => mbc.load_state(state),
+            (2, Self::Mbc2(mbc)) => mbc.load_state(state),
+            (3, Self::Mbc3(mbc)) => mbc.load_state(state),
+            (5, Self::Mbc5(mbc)) => mbc.load_state(state),
+            _ => Err("The save state has another memory bank controller".to_string()),
+        }
+    }
+}
@@ -0,0 +1,320 @@
+use crate::util::helper::{is_bit_set, set_bit};
+use crate::util::state::{Snapshot, StateReader, StateWriter};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const CYCLES_PER_SECOND: u32 = 4_194_304;
+// 5 registers and 5 latched registers as u32 and a u64 timestamp
+pub const RTC_SAVE_SIZE: usize = 48;
+
+/// What advances the real time clock
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RtcClock {
+    Emulated, // emulated cycles, deterministic and paused with the emulator
+    WallTime, // the host's clock, keeps running while the emulator is closed
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/// The MBC3 real time clock
+#[derive(Debug)]
+pub struct Rtc {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    days: u16, // 9-bit day counter
+    is_halted: bool,
+    day_carry: bool,
+
+    latched: [u8; 5], // RTC S, M, H, DL, DH as read by the CPU
+    clock: RtcClock,
+    cycles: u32,    // emulated cycles of the current second
+    last_sync: u64, // unix time the wall clock was last applied
+}
+
+impl Rtc {
+    pub fn new(clock: RtcClock) -> Self {
+        Rtc {
+            seconds: 0,
+            minutes: 0,
+            hours: 0,
+            days: 0,
+            is_halted: false,
+            day_carry: false,
+
+            latched: [0; 5],
+            clock,
+            cycles: 0,
+            last_sync: unix_time(),
+        }
+    }
+
+    pub fn set_clock(&mut self, clock: RtcClock) {
+        self.sync();
+        self.clock = clock;
+        self.last_sync = unix_time();
+    }
+
+    // One machine cycle
+    pub fn tick(&mut self) {
+        if self.clock != RtcClock::Emulated || self.is_halted {
+            return;
+        }
+        self.cycles += 4;
+        if self.cycles >= CYCLES_PER_SECOND {
+            self.cycles -= CYCLES_PER_SECOND;
+            self.advance(1);
+        }
+    }
+
+    // Catches up with the host's clock
+    fn sync(&mut self) {
+        if self.clock != RtcClock::WallTime {
+            return;
+        }
+        let now = unix_time();
+        if !self.is_halted {
+            self.advance(now.saturating_sub(self.last_sync));
+        }
+        self.last_sync = now;
+    }
+
+    fn advance(&mut self, mut seconds: u64) {
+        // The counters can hold invalid values written by the CPU, those
+        // count on until they overflow their bit width, a second at a time
+        while seconds > 0 && (self.seconds >= 60 || self.minutes >= 60 || self.hours >= 24) {
+            self.count_second();
+            seconds -= 1;
+        }
+        if seconds == 0 {
+            return;
+        }
+        let mut total = self.seconds as u64 + seconds;
+        self.seconds = (total % 60) as u8;
+        total = total / 60 + self.minutes as u64;
+        self.minutes = (total % 60) as u8;
+        total = total / 60 + self.hours as u64;
+        self.hours = (total % 24) as u8;
+        total = total / 24 + self.days as u64;
+        if total > 0x1FF {
+            self.day_carry = true;
+        }
+        self.days = (total & 0x1FF) as u16;
+    }
+
+    // Only the step past the last valid value carries into the next counter,
+    // an invalid value wraps to 0 at the end of its bit width without a carry
+    fn count_second(&mut self) {
+        self.seconds = (self.seconds + 1) & 0b11_1111;
+        if self.seconds != 60 {
+            return;
+        }
+        self.seconds = 0;
+        self.minutes = (self.minutes + 1) & 0b11_1111;
+        if self.minutes != 60 {
+            return;
+        }
+        self.minutes = 0;
+        self.hours = (self.hours + 1) & 0b1_1111;
+        if self.hours != 24 {
+            return;
+        }
+        self.hours = 0;
+        self.days += 1;
+        if self.days > 0x1FF {
+            self.days = 0;
+            self.day_carry = true;
+        }
+    }
+
+    fn registers(&self) -> [u8; 5] {
+        let mut day_high = (self.days >> 8) as u8;
+        day_high = set_bit(day_high, 6, self.is_halted);
+        day_high = set_bit(day_high, 7, self.day_carry);
+        [
+            self.seconds,
+            self.minutes,
+            self.hours,
+            self.days as u8,
+            day_high,
+        ]
+    }
+
+    fn set_registers(&mut self, registers: [u8; 5]) {
+        self.seconds = registers[0] & 0b11_1111;
+        self.minutes = registers[1] & 0b11_1111;
+        self.hours = registers[2] & 0b1_1111;
+        self.days = (self.days & 0x100) | registers[3] as u16;
+        self.days = (self.days & 0xFF) | ((registers[4] as u16 & 1) << 8);
+        self.is_halted = is_bit_set(registers[4], 6);
+        self.day_carry = is_bit_set(registers[4], 7);
+    }
+
+    /// Copies the running counters into the registers the CPU reads
+    pub fn latch(&mut self) {
+        self.sync();
+        self.latched = self.registers();
+    }
+
+    /// `register` is 0x08-0x0C
+    pub fn read(&self, register: u8) -> u8 {
+        self.latched[(register - 0x08) as usize]
+    }
+
+    pub fn write(&mut self, register: u8, data: u8) {
+        self.sync();
+        let index = (register - 0x08) as usize;
+        let mut registers = self.registers();
+        registers[index] = data;
+        self.set_registers(registers);
+        if index == 0 {
+            // writing the seconds restarts the current second
+            self.cycles = 0;
+        }
+        self.latched[index] = self.registers()[index];
+    }
+
+    /// The 48 byte RTC block other emulators append to the save RAM
+    pub fn save(&mut self) -> Vec<u8> {
+        self.sync();
+        let mut bytes = Vec::with_capacity(RTC_SAVE_SIZE);
+        for register in self.registers().iter().chain(self.latched.iter()) {
+            bytes.extend_from_slice(&(*register as u32).to_le_bytes());
+        }
+        bytes.extend_from_slice(&unix_time().to_le_bytes());
+        bytes
+    }
+
+    pub fn load(&mut self, bytes: &[u8]) -> Result<(), String> {
+        if bytes.len() < RTC_SAVE_SIZE {
+            return Err(format!("RTC block is too small ({} bytes)", bytes.len()));
+        }
+        let register = |index: usize| bytes[index * 4];
+        self.set_registers([0, 1, 2, 3, 4].map(register));
+        self.latched = [5, 6, 7, 8, 9].map(register);
+
+        let mut timestamp = [0; 8];
+        timestamp.copy_from_slice(&bytes[40..48]);
+        self.last_sync = u64::from_le_bytes(timestamp);
+        self.cycles = 0;
+        if self.clock == RtcClock::WallTime {
+            // account for the time the game was not running
+            self.sync();
+        } else {
+            self.last_sync = unix_time();
+        }
+        Ok(())
+    }
+}
+
+// Which clock advances the RTC is a setting and not part of the state
+impl Snapshot for Rtc {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.seconds);
+        state.u8(self.minutes);
+        state.u8(self.hours);
+        state.u16(self.days);
+        state.bool(self.is_halted);
+        state.bool(self.day_carry);
+        state.bytes(&self.latched);
+        state.u32(self.cycles);
+        state.u64(self.last_sync);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.seconds = state.u8()?;
+        self.minutes = state.u8()?;
+        self.hours = state.u8()?;
+        self.days = state.u16()? & 0x1FF;
+        self.is_halted = state.bool()?;
+        self.day_carry = state.bool()?;
+        state.bytes_into(&mut self.latched)?;
+        self.cycles = state.u32()? % CYCLES_PER_SECOND;
+        self.last_sync = state.u64()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn emulated_clock_and_latch() {
+        let mut rtc = Rtc::new(RtcClock::Emulated);
+        rtc.write(0x08, 59);
+        rtc.write(0x09, 59);
+        rtc.write(0x0A, 23);
+        for _ in 0..CYCLES_PER_SECOND / 4 {
+            rtc.tick();
+        }
+        // latched registers only change on a latch
+        assert_eq!(rtc.read(0x08), 59);
+        rtc.latch();
+        assert_eq!(rtc.read(0x08), 0);
+        assert_eq!(rtc.read(0x09), 0);
+        assert_eq!(rtc.read(0x0A), 0);
+        assert_eq!(rtc.read(0x0B), 1);
+    }
+
+    #[test]
+    fn halt_and_day_carry() {
+        let mut rtc = Rtc::new(RtcClock::Emulated);
+        rtc.write(0x0B, 0xFF);
+        rtc.write(0x0C, 0b0100_0001);
+        for _ in 0..CYCLES_PER_SECOND / 4 {
+            rtc.tick();
+        }
+        rtc.latch();
+        assert_eq!(rtc.read(0x08), 0);
+
+        rtc.write(0x0A, 23);
+        rtc.write(0x09, 59);
+        rtc.write(0x08, 59);
+        rtc.write(0x0C, 0b0000_0001);
+        rtc.advance(1);
+        rtc.latch();
+        assert_eq!(rtc.read(0x0B), 0);
+        assert_eq!(rtc.read(0x0C), 0b1000_0000);
+    }
+
+    #[test]
+    fn invalid_values_wrap_without_carry() {
+        let mut rtc = Rtc::new(RtcClock::Emulated);
+        rtc.write(0x08, 62);
+        rtc.write(0x09, 10);
+        rtc.advance(3);
+        rtc.latch();
+        assert_eq!(rtc.read(0x08), 1);
+        assert_eq!(rtc.read(0x09), 10);
+
+        // hours 30 count to 31 and wrap to 0 without a day passing
+        rtc.write(0x08, 0);
+        rtc.write(0x09, 0);
+        rtc.write(0x0A, 30);
+        rtc.advance(2 * 60 * 60 + 5);
+        rtc.latch();
+        assert_eq!(rtc.read(0x08), 5);
+        assert_eq!(rtc.read(0x0A), 0);
+        assert_eq!(rtc.read(0x0B), 0);
+    }
+
+    #[test]
+    fn save_and_load() {
+        let mut rtc = Rtc::new(RtcClock::Emulated);
+        rtc.write(0x09, 42);
+        rtc.write(0x0C, 0b0100_0000);
+        rtc.latch();
+        let bytes = rtc.save();
+        assert_eq!(bytes.len(), RTC_SAVE_SIZE);
+
+        let mut loaded = Rtc::new(RtcClock::Emulated);
+        loaded.load(&bytes).unwrap();
+        assert_eq!(loaded.read(0x09), 42);
+        assert_eq!(loaded.read(0x0C), 0b0100_0000);
+    }
+}
@@ -1,8 +1,15 @@
+pub mod apu;
 pub mod bus;
 pub mod cartridge;
 pub mod dma;
 pub mod interrupts;
+pub mod joypad;
 pub mod lcd;
+pub mod mbc;
 pub mod oam;
+pub mod palette;
 pub mod ppu;
+pub mod printer;
+pub mod serial;
+pub mod sgb;
 pub mod timer;
@@ -1,4 +1,47 @@
+use crate::util::helper::is_bit_set;
+use crate::util::state::{Snapshot, StateReader, StateWriter};
+
 const OAM_SIZE: usize = 160;
+pub const SPRITE_COUNT: u8 = 40;
+
+/// One of the 40 4-byte entries in OAM
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sprite {
+    pub y: u8, // vertical position plus 16
+    pub x: u8, // horizontal position plus 8
+    pub tile: u8,
+    pub flags: u8,
+}
+
+impl Sprite {
+    // BG and window colors 1-3 are drawn over this sprite
+    pub fn bg_priority(&self) -> bool {
+        is_bit_set(self.flags, 7)
+    }
+
+    pub fn y_flip(&self) -> bool {
+        is_bit_set(self.flags, 6)
+    }
+
+    pub fn x_flip(&self) -> bool {
+        is_bit_set(self.flags, 5)
+    }
+
+    // false: OBP0, true: OBP1
+    pub fn uses_obj_palette_1(&self) -> bool {
+        is_bit_set(self.flags, 4)
+    }
+
+    // CGB only
+    pub fn vram_bank(&self) -> u8 {
+        (self.flags >> 3) & 1
+    }
+
+    // CGB only, OCP palette 0-7
+    pub fn color_palette(&self) -> u8 {
+        self.flags & 0b111
+    }
+}
 
 #[derive(Debug)]
 pub struct Oam {
@@ -19,4 +62,47 @@ impl Oam {
     pub fn write(&mut self, offset: u8, data: u8) {
         self.data[offset as usize] = data
     }
+
+    pub fn sprite(&self, index: u8) -> Sprite {
+        let offset = index as usize * 4;
+        Sprite {
+            y: self.data[offset],
+            x: self.data[offset + 1],
+            tile: self.data[offset + 2],
+            flags: self.data[offset + 3],
+        }
+    }
+}
+
+impl Snapshot for Oam {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.data);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        state.bytes_into(&mut self.data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_sprite() {
+        let mut oam = Oam::new();
+        oam.write(4, 0x20);
+        oam.write(5, 0x18);
+        oam.write(6, 0x42);
+        oam.write(7, 0b1011_0000);
+
+        let sprite = oam.sprite(1);
+        assert_eq!(sprite.y, 0x20);
+        assert_eq!(sprite.x, 0x18);
+        assert_eq!(sprite.tile, 0x42);
+        assert!(sprite.bg_priority());
+        assert!(!sprite.y_flip());
+        assert!(sprite.x_flip());
+        assert!(sprite.uses_obj_palette_1());
+    }
 }
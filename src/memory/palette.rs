@@ -0,0 +1,103 @@
+use crate::util::state::{Snapshot, StateReader, StateWriter};
+
+const PALETTE_RAM_SIZE: usize = 64;
+
+/// CGB color palette RAM: 8 palettes of 4 RGB555 colors,
+/// accessed through BCPS/BCPD for the background and OCPS/OCPD for objects
+#[derive(Debug)]
+pub struct ColorPalettes {
+    data: [u8; PALETTE_RAM_SIZE],
+    index: u8, // byte selected by the specification register
+    auto_increment: bool,
+}
+
+impl ColorPalettes {
+    pub fn new() -> Self {
+        ColorPalettes {
+            // all white
+            data: [0xFF; PALETTE_RAM_SIZE],
+            index: 0,
+            auto_increment: false,
+        }
+    }
+
+    pub fn read_spec(&self) -> u8 {
+        let auto_increment = if self.auto_increment { 0x80 } else { 0 };
+        auto_increment | 0x40 | self.index
+    }
+
+    pub fn write_spec(&mut self, data: u8) {
+        self.index = data & 0x3F;
+        self.auto_increment = data & 0x80 != 0;
+    }
+
+    pub fn read_data(&self) -> u8 {
+        self.data[self.index as usize]
+    }
+
+    pub fn write_data(&mut self, data: u8) {
+        self.data[self.index as usize] = data;
+        if self.auto_increment {
+            self.index = (self.index + 1) & 0x3F;
+        }
+    }
+
+    /// The color as RGB888
+    pub fn color(&self, palette: u8, color_id: u8) -> (u8, u8, u8) {
+        let offset = palette as usize * 8 + color_id as usize * 2;
+        rgb555_to_rgb888(u16::from_le_bytes([
+            self.data[offset],
+            self.data[offset + 1],
+        ]))
+    }
+}
+
+/// Converts a little endian RGB555 color as used by the CGB and SGB
+pub fn rgb555_to_rgb888(rgb555: u16) -> (u8, u8, u8) {
+    // scales 5 to 8 bits so 0x1F becomes 0xFF
+    let channel = |shift: u16| {
+        let value = ((rgb555 >> shift) & 0x1F) as u8;
+        (value << 3) | (value >> 2)
+    };
+    (channel(0), channel(5), channel(10))
+}
+
+impl Snapshot for ColorPalettes {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.data);
+        state.u8(self.index);
+        state.bool(self.auto_increment);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        state.bytes_into(&mut self.data)?;
+        self.index = state.u8()? & 0x3F;
+        self.auto_increment = state.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn auto_increment_and_colors() {
+        let mut palettes = ColorPalettes::new();
+        // palette 1, color 2
+        palettes.write_spec(0x80 | 12);
+        palettes.write_data(0x1F); // red
+        palettes.write_data(0x7C); // blue
+        assert_eq!(palettes.read_spec(), 0xC0 | 14);
+        assert_eq!(palettes.color(1, 2), (0xFF, 0x00, 0xFF));
+
+        // without auto increment both writes go to the red/green byte
+        palettes.write_spec(12);
+        palettes.write_data(0xFF);
+        palettes.write_data(0x00);
+        assert_eq!(palettes.read_spec(), 0x40 | 12);
+        assert_eq!(palettes.read_data(), 0x00);
+        assert_eq!(palettes.color(1, 2), (0x00, 0x00, 0xFF));
+        assert_eq!(palettes.color(1, 3), (0xFF, 0xFF, 0xFF));
+    }
+}
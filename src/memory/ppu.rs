@@ -1,17 +1,26 @@
-use sdl2::{
-    pixels::PixelFormatEnum,
-    rect::Rect,
-    render::{TextureCreator, WindowCanvas},
-    video::WindowContext,
-    Sdl,
-};
-
-use crate::util::helper::is_bit_set;
-
 use super::bus::Bus;
+use super::interrupts::Interrupt;
+use super::lcd::{Lcd, LcdMode};
+use super::oam::{Sprite, SPRITE_COUNT};
+use super::sgb::Sgb;
+use crate::util::state::{Snapshot, StateReader, StateWriter};
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
 
 // grid of 20x20 8x8 tiles with 3 color channels
-const TILE_DATA_SIZE: usize = 20 * 20 * 8 * 8 * 3;
+pub const TILE_DATA_WIDTH: usize = 20 * 8;
+const TILE_DATA_SIZE: usize = TILE_DATA_WIDTH * TILE_DATA_WIDTH * 3;
+
+// the whole 32x32 tile background map with 3 color channels
+pub const BG_MAP_WIDTH: usize = 32 * 8;
+const BG_MAP_SIZE: usize = BG_MAP_WIDTH * BG_MAP_WIDTH * 3;
+
+const DOTS_PER_LINE: u16 = 456;
+const OAM_SEARCH_DOTS: u16 = 80;
+const PIXEL_TRANSFER_DOTS: u16 = 172;
+const LINES_PER_FRAME: u8 = 154;
+const MAX_SPRITES_PER_LINE: usize = 10;
 
 const COLORS: [(u8, u8, u8); 4] = [
     (0xE0, 0xF8, 0xD0), // 00 White
@@ -20,42 +29,69 @@ const COLORS: [(u8, u8, u8); 4] = [
     (0x08, 0x18, 0x20), // 11 Black
 ];
 
+// Maps the two bits of a pixel in a tile row to a color id.
+// The first byte of a row holds the lower bits, the second the upper bits.
+pub fn tile_color_id(low: u8, high: u8, x: u8) -> u8 {
+    let bit = 7 - x;
+    (((high >> bit) & 1) << 1) | ((low >> bit) & 1)
+}
+
+// Maps a color id to one of the four shades with a BGP/OBP palette
+pub fn palette_shade(palette: u8, color_id: u8) -> u8 {
+    (palette >> (color_id * 2)) & 0b11
+}
+
 pub struct Ppu {
-    canvas: WindowCanvas,
-    texture_creator: TextureCreator<WindowContext>,
-    tile_data: [u8; TILE_DATA_SIZE],
-    bg_buffer: [u8; 160 * 144 * 3],
-    // tile_data_canvas: WindowCanvas,
+    dots: u16,                           // dots elapsed in the current line
+    stat_line: bool,                     // STAT interrupts are only requested on a rising edge
+    window_line: u8, // the window's own line counter, only advanced when it is drawn
+    bg_color_ids: [u8; SCREEN_WIDTH], // BG/window color ids of the current line
+    bg_priorities: [bool; SCREEN_WIDTH], // CGB: BG attribute bit 7 of the current line
+    line_sprites: Vec<Sprite>, // sprites selected by the OAM scan of the current line
+    tile_data: Vec<u8>,
+    bg_map: Vec<u8>,
+    bg_buffer: Vec<u8>,
+    shades: Vec<u8>, // DMG shade of each pixel, colored by the SGB
 }
 
 impl Ppu {
-    pub fn new(sdl_context: &Sdl) -> Ppu {
-        let video_subsystem = sdl_context.video().unwrap();
+    pub fn new() -> Ppu {
+        Ppu {
+            dots: 0,
+            stat_line: false,
+            window_line: 0,
+            bg_color_ids: [0; SCREEN_WIDTH],
+            bg_priorities: [false; SCREEN_WIDTH],
+            line_sprites: Vec::with_capacity(MAX_SPRITES_PER_LINE),
+            tile_data: vec![0x40; TILE_DATA_SIZE],
+            bg_map: vec![0; BG_MAP_SIZE],
+            bg_buffer: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT * 3],
+            shades: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+        }
+    }
 
-        let window = video_subsystem
-            .window("Game Boy", 256 + 160, 256 + 160)
-            .position_centered()
-            .build()
-            .expect("could not initialize video subsystem");
+    /// The 160x144 LCD image as RGB24
+    pub fn frame_buffer(&self) -> &[u8] {
+        &self.bg_buffer
+    }
 
-        let canvas = window
-            .into_canvas()
-            .build()
-            .expect("could not make a canvas");
+    /// The 160x144 shades after applying BGP, OBP0 and OBP1
+    pub fn shades(&self) -> &[u8] {
+        &self.shades
+    }
 
-        let texture_creator = canvas.texture_creator();
+    /// All 384 tiles in VRAM as a 160x160 RGB24 image, for debugging
+    pub fn tile_data(&self) -> &[u8] {
+        &self.tile_data
+    }
 
-        Ppu {
-            canvas,
-            texture_creator,
-            tile_data: [0x40; TILE_DATA_SIZE],
-            bg_buffer: [0; 160 * 144 * 3],
-        }
+    /// The whole 256x256 background map as RGB24, for debugging
+    pub fn bg_map(&self) -> &[u8] {
+        &self.bg_map
     }
 
-    fn update_tile_data(bus: &mut Bus, start_address: u16) {
+    fn update_tile_data(bus: &mut Bus) {
         let mut addr = 0x8000;
-        // eprintln!("start_address {:x}", addr);
         for tile in 0..384 {
             let start_x = (tile % 20) * 8;
             let start_y = (tile / 20) * 8;
@@ -64,101 +100,41 @@ impl Ppu {
         }
     }
 
+    /// Renders the tile data and background map debug views
     pub fn draw(bus: &mut Bus) {
-        let lcdc_control = bus.read(0xFF40);
+        Self::update_tile_data(bus);
 
-        let lcd_enabled = is_bit_set(lcdc_control, 7);
-        let tile_map_area = is_bit_set(lcdc_control, 6);
-        let window_enabled = is_bit_set(lcdc_control, 5);
-        let (tile_data_start_addr, relative_addr_mode) = if is_bit_set(lcdc_control, 4) {
-            (0x8800, true)
-        } else {
-            (0x8000, false)
-        };
         let start_addr = bus.lcd.bg_map_area();
-
-        Self::update_tile_data(bus, tile_data_start_addr);
-
-        // eprintln!("start addr {:x}", start_addr);
-
-        // eprintln!("SCY: {} SCX: {}", bus.lcd.scroll_y, bus.lcd.scroll_x);
-
-        let mut texture = bus
-            .ppu
-            .texture_creator
-            .create_texture_streaming(PixelFormatEnum::RGB24, 160, 160)
-            .expect("Couldn't create texture!");
-        texture
-            .update(None, &bus.ppu.tile_data, 20 * 8 * 3)
-            .expect("");
-
-        bus.ppu.canvas.clear();
-
         for tile_number in 0..1024 {
             let addr = start_addr + tile_number;
-            let tile_id = bus.read(addr) as i32;
-            // let tile_id = if relative_addr_mode {
-            //     tile_id_read + (-128)
-            // } else {
-            //     tile_id_read
-            // } as i32;
-            // bus.read(cpu, addr) as i32;
+            let tile_id = bus.read(addr) as usize;
             let tile_x = (tile_id % 20) * 8;
             let tile_y = (tile_id / 20) * 8;
 
-            let target_x = ((tile_number % 32) * 8) as i32;
-            let target_y = ((tile_number / 32) * 8) as i32;
+            let target_x = (tile_number as usize % 32) * 8;
+            let target_y = (tile_number as usize / 32) * 8;
 
-            bus.ppu
-                .canvas
-                .copy(
-                    &texture,
-                    Some(Rect::new(tile_x, tile_y, 8, 8)),
-                    Some(Rect::new(target_x, target_y, 8, 8)),
-                )
-                .expect("");
+            for row in 0..8 {
+                let source = ((tile_y + row) * TILE_DATA_WIDTH + tile_x) * 3;
+                let target = ((target_y + row) * BG_MAP_WIDTH + target_x) * 3;
+                bus.ppu.bg_map[target..target + 8 * 3]
+                    .copy_from_slice(&bus.ppu.tile_data[source..source + 8 * 3]);
+            }
         }
-
-        bus.ppu
-            .canvas
-            .copy(&texture, None, Some(Rect::new(256, 0, 20 * 8, 20 * 8)))
-            .expect("");
-        bus.ppu.canvas.present();
-    }
-
-    fn draw_background(&self, bus: &Bus) {
-        let lcdc_control = bus.read(0xFF40);
-
-        let lcd = &bus.lcd;
-
-        let lcd_enabled = is_bit_set(lcdc_control, 7);
-        let tile_map_area = is_bit_set(lcdc_control, 6);
-        let window_enabled = is_bit_set(lcdc_control, 5);
-        let (tile_data_start_addr, relative_addr_mode) = if is_bit_set(lcdc_control, 4) {
-            (0x8000, false)
-        } else {
-            (0x8800, true)
-        };
-        let start_addr = bus.lcd.bg_map_area();
-
-        let scroll_y = bus.read(0xff42);
-        let scroll_x = bus.read(0xff43);
     }
 
-    fn draw_tile_into_texture(bus: &mut Bus, addr: u16, start_x: i32, start_y: i32) {
+    fn draw_tile_into_texture(bus: &mut Bus, addr: u16, start_x: usize, start_y: usize) {
         let mut addr = addr;
         for pixel_y in 0..8 {
             let byte1 = bus.read(addr);
             addr += 1;
             let byte2 = bus.read(addr);
             addr += 1;
-            for shift in (0..8).rev() {
-                let higher = ((byte1 >> shift) & 1) << 1;
-                let lower = (byte2 >> shift) & 1;
-                let color_id = higher | lower;
-                let pos_x = (7 - shift + start_x) as usize;
-                let pos_y = (pixel_y + start_y) as usize;
-                let pos_buf = (pos_y * 160 + pos_x) * 3;
+            for x in 0..8 {
+                let color_id = tile_color_id(byte1, byte2, x);
+                let pos_x = x as usize + start_x;
+                let pos_y = pixel_y + start_y;
+                let pos_buf = (pos_y * TILE_DATA_WIDTH + pos_x) * 3;
 
                 (
                     bus.ppu.tile_data[pos_buf],
@@ -169,5 +145,484 @@ impl Ppu {
         }
     }
 
-    pub fn tick(bus: &mut Bus) {}
+    // Address of a BG/window tile, LCDC bit 4 selects between unsigned tile ids
+    // from 0x8000 and signed tile ids relative to 0x9000
+    pub fn bgw_tile_address(lcd: &Lcd, tile_id: u8) -> u16 {
+        if lcd.bgw_data_area() == 0x8000 {
+            0x8000 + tile_id as u16 * 16
+        } else {
+            (0x9000 + tile_id as i8 as i32 * 16) as u16
+        }
+    }
+
+    // Color id and CGB attributes of the pixel at (x, y) in the 256x256 tile map at map_area
+    fn fetch_bgw_pixel(bus: &Bus, map_area: u16, x: u8, y: u8) -> (u8, u8) {
+        let map_address = map_area + (y as u16 / 8) * 32 + (x as u16 / 8);
+        let tile_id = bus.read_vram(0, map_address);
+        // On CGB the attributes are at the same address in VRAM bank 1:
+        // bits 0-2 palette, 3 tile bank, 5 x flip, 6 y flip, 7 BG priority
+        let attributes = if bus.is_cgb_mode {
+            bus.read_vram(1, map_address)
+        } else {
+            0
+        };
+        let mut row = y % 8;
+        if attributes & 0x40 != 0 {
+            row = 7 - row;
+        }
+        let mut column = x % 8;
+        if attributes & 0x20 != 0 {
+            column = 7 - column;
+        }
+        let bank = (attributes >> 3) & 1;
+        let row_address = Self::bgw_tile_address(&bus.lcd, tile_id) + row as u16 * 2;
+        let low = bus.read_vram(bank, row_address);
+        let high = bus.read_vram(bank, row_address + 1);
+        (tile_color_id(low, high, column), attributes)
+    }
+
+    fn set_pixel(&mut self, x: usize, y: usize, shade: u8) {
+        self.shades[y * SCREEN_WIDTH + x] = shade;
+        self.set_color(x, y, COLORS[shade as usize]);
+    }
+
+    fn set_color(&mut self, x: usize, y: usize, color: (u8, u8, u8)) {
+        let pos_buf = (y * SCREEN_WIDTH + x) * 3;
+        (
+            self.bg_buffer[pos_buf],
+            self.bg_buffer[pos_buf + 1],
+            self.bg_buffer[pos_buf + 2],
+        ) = color;
+    }
+
+    fn render_scanline(bus: &mut Bus) {
+        let ly = bus.lcd.ly;
+        // In CGB mode LCDC bit 0 only removes the BG priority over sprites
+        let bgw_enabled = bus.lcd.bg_window_enabled() || bus.is_cgb_mode;
+        // WX is the window position plus 7
+        let window_visible = bgw_enabled && bus.lcd.win_enable() && ly >= bus.lcd.win_y;
+        let mut window_drawn = false;
+
+        for x in 0..SCREEN_WIDTH as u8 {
+            let (color_id, attributes) = if !bgw_enabled {
+                (0, 0)
+            } else if window_visible && x as u16 + 7 >= bus.lcd.win_x as u16 {
+                window_drawn = true;
+                let window_x = (x as u16 + 7 - bus.lcd.win_x as u16) as u8;
+                Self::fetch_bgw_pixel(bus, bus.lcd.win_map_area(), window_x, bus.ppu.window_line)
+            } else {
+                Self::fetch_bgw_pixel(
+                    bus,
+                    bus.lcd.bg_map_area(),
+                    x.wrapping_add(bus.lcd.scroll_x),
+                    ly.wrapping_add(bus.lcd.scroll_y),
+                )
+            };
+            bus.ppu.bg_color_ids[x as usize] = color_id;
+            bus.ppu.bg_priorities[x as usize] = attributes & 0x80 != 0;
+            if bus.is_cgb_mode {
+                let color = bus.lcd.bg_colors.color(attributes & 0b111, color_id);
+                bus.ppu.set_color(x as usize, ly as usize, color);
+            } else {
+                let shade = palette_shade(bus.lcd.bg_palette, color_id);
+                bus.ppu.set_pixel(x as usize, ly as usize, shade);
+            }
+        }
+
+        if window_drawn {
+            bus.ppu.window_line += 1;
+        }
+
+        if bus.lcd.obj_enabled() {
+            Self::render_sprites(bus);
+        }
+    }
+
+    // Selects the first 10 sprites in OAM order that overlap the current line
+    fn scan_oam(bus: &mut Bus) {
+        let ly = bus.lcd.ly as u16;
+        let height = bus.lcd.obj_height() as u16;
+        bus.ppu.line_sprites.clear();
+        for index in 0..SPRITE_COUNT {
+            let sprite = bus.oam.sprite(index);
+            let top = sprite.y as u16;
+            if ly + 16 >= top && ly + 16 < top + height {
+                bus.ppu.line_sprites.push(sprite);
+                if bus.ppu.line_sprites.len() == MAX_SPRITES_PER_LINE {
+                    break;
+                }
+            }
+        }
+        // On DMG the sprite with the smaller X coordinate is drawn on top,
+        // the stable sort keeps the OAM order for equal X coordinates.
+        // CGB games only use the OAM order unless OPRI selects the DMG behavior.
+        if !bus.is_cgb_mode || bus.lcd.object_priority & 1 != 0 {
+            bus.ppu.line_sprites.sort_by_key(|sprite| sprite.x);
+        }
+    }
+
+    fn sprite_color_id(bus: &Bus, sprite: &Sprite, x: u8) -> u8 {
+        let height = bus.lcd.obj_height();
+        // the height can change after the sprite was found in the OAM scan
+        let mut row = (bus.lcd.ly + 16 - sprite.y) & (height - 1);
+        if sprite.y_flip() {
+            row = height - 1 - row;
+        }
+        let mut column = x + 8 - sprite.x;
+        if sprite.x_flip() {
+            column = 7 - column;
+        }
+        // In 8x16 mode the lowest bit of the tile id is ignored
+        let tile = if height == 16 {
+            sprite.tile & 0xFE
+        } else {
+            sprite.tile
+        };
+        let bank = if bus.is_cgb_mode {
+            sprite.vram_bank()
+        } else {
+            0
+        };
+        // Sprites always use the unsigned 0x8000 addressing
+        let row_address = 0x8000 + tile as u16 * 16 + row as u16 * 2;
+        let low = bus.read_vram(bank, row_address);
+        let high = bus.read_vram(bank, row_address + 1);
+        tile_color_id(low, high, column)
+    }
+
+    // The highest priority sprite with a non-transparent pixel at x
+    fn sprite_pixel_at(bus: &Bus, x: u8) -> Option<(Sprite, u8)> {
+        bus.ppu
+            .line_sprites
+            .iter()
+            .filter(|sprite| x as u16 + 8 >= sprite.x as u16 && (x as u16) < sprite.x as u16)
+            .map(|sprite| (*sprite, Self::sprite_color_id(bus, sprite, x)))
+            // Color 0 is transparent and lets lower priority sprites through
+            .find(|(_, color_id)| *color_id != 0)
+    }
+
+    fn render_sprites(bus: &mut Bus) {
+        let ly = bus.lcd.ly as usize;
+        for x in 0..SCREEN_WIDTH as u8 {
+            let Some((sprite, color_id)) = Self::sprite_pixel_at(bus, x) else {
+                continue;
+            };
+            let bg_color_id = bus.ppu.bg_color_ids[x as usize];
+            let is_behind_bg = if bus.is_cgb_mode {
+                // with LCDC bit 0 cleared sprites are always on top
+                bus.lcd.bg_window_enabled()
+                    && bg_color_id != 0
+                    && (sprite.bg_priority() || bus.ppu.bg_priorities[x as usize])
+            } else {
+                sprite.bg_priority() && bg_color_id != 0
+            };
+            if is_behind_bg {
+                continue;
+            }
+            if bus.is_cgb_mode {
+                let color = bus.lcd.obj_colors.color(sprite.color_palette(), color_id);
+                bus.ppu.set_color(x as usize, ly, color);
+                continue;
+            }
+            let palette = if sprite.uses_obj_palette_1() {
+                bus.lcd.obj_palette_1
+            } else {
+                bus.lcd.obj_palette_0
+            };
+            let shade = palette_shade(palette, color_id);
+            bus.ppu.set_pixel(x as usize, ly, shade);
+        }
+    }
+
+    fn mode_at(line: u8, dots: u16) -> LcdMode {
+        if line >= SCREEN_HEIGHT as u8 {
+            LcdMode::VBlank
+        } else if dots < OAM_SEARCH_DOTS {
+            LcdMode::SearchingOam
+        } else if dots < OAM_SEARCH_DOTS + PIXEL_TRANSFER_DOTS {
+            LcdMode::TransferingData
+        } else {
+            LcdMode::HBlank
+        }
+    }
+
+    /// Advances the PPU by one machine cycle (4 dots)
+    pub fn tick(bus: &mut Bus) {
+        if !bus.lcd.lcd_enable() {
+            // LY is fixed at 0 and the mode is HBlank while the LCD is off
+            bus.ppu.dots = 0;
+            bus.ppu.stat_line = false;
+            bus.ppu.window_line = 0;
+            bus.lcd.ly = 0;
+            bus.lcd.set_mode(LcdMode::HBlank);
+            return;
+        }
+
+        bus.ppu.dots += 4;
+        if bus.ppu.dots == DOTS_PER_LINE {
+            bus.ppu.dots = 0;
+            bus.lcd.ly = (bus.lcd.ly + 1) % LINES_PER_FRAME;
+            if bus.lcd.ly == 0 {
+                bus.ppu.window_line = 0;
+            }
+        }
+
+        let mode = Self::mode_at(bus.lcd.ly, bus.ppu.dots);
+        if mode != bus.lcd.mode() {
+            bus.lcd.set_mode(mode);
+            match mode {
+                LcdMode::SearchingOam => Self::scan_oam(bus),
+                LcdMode::HBlank => {
+                    Self::render_scanline(bus);
+                    bus.hdma.start_hblank_block();
+                }
+                LcdMode::VBlank => {
+                    bus.int.request_interrupt(Interrupt::VBlank);
+                    if bus.is_sgb_mode {
+                        Sgb::vblank(bus);
+                    }
+                }
+                _ => (),
+            }
+        }
+        bus.lcd.set_lyc(bus.lcd.ly == bus.lcd.ly_compare);
+
+        let stat_line = bus.lcd.stat_interrupt_line();
+        if stat_line && !bus.ppu.stat_line {
+            bus.int.request_interrupt(Interrupt::LcdStat);
+        }
+        bus.ppu.stat_line = stat_line;
+    }
+}
+
+impl Snapshot for Ppu {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u16(self.dots);
+        state.bool(self.stat_line);
+        state.u8(self.window_line);
+        state.u8(self.line_sprites.len() as u8);
+        for sprite in &self.line_sprites {
+            state.u8(sprite.y);
+            state.u8(sprite.x);
+            state.u8(sprite.tile);
+            state.u8(sprite.flags);
+        }
+        state.bytes(&self.bg_buffer);
+        state.bytes(&self.shades);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.dots = state.u16()?;
+        if self.dots >= DOTS_PER_LINE || !self.dots.is_multiple_of(4) {
+            return Err(format!("Invalid PPU dot {}", self.dots));
+        }
+        self.stat_line = state.bool()?;
+        self.window_line = state.u8()?;
+        let sprite_count = (state.u8()? as usize).min(MAX_SPRITES_PER_LINE);
+        self.line_sprites.clear();
+        for _ in 0..sprite_count {
+            self.line_sprites.push(Sprite {
+                y: state.u8()?,
+                x: state.u8()?,
+                tile: state.u8()?,
+                flags: state.u8()?,
+            });
+        }
+        state.bytes_into(&mut self.bg_buffer)?;
+        state.bytes_into(&mut self.shades)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::cartridge::Cartridge;
+    use crate::memory::lcd::Palette;
+    use crate::model::Model;
+
+    fn bus() -> Bus {
+        Bus::new(Cartridge::from_bytes(vec![0; 0x8000]).unwrap())
+    }
+
+    fn run_machine_cycles(bus: &mut Bus, machine_cycles: u32) {
+        for _ in 0..machine_cycles {
+            Ppu::tick(bus);
+        }
+    }
+
+    #[test]
+    fn modes_within_a_line() {
+        let mut bus = bus();
+        run_machine_cycles(&mut bus, 1);
+        assert_eq!(bus.lcd.mode(), LcdMode::SearchingOam);
+        run_machine_cycles(&mut bus, 20);
+        assert_eq!(bus.lcd.mode(), LcdMode::TransferingData);
+        run_machine_cycles(&mut bus, 43);
+        assert_eq!(bus.lcd.mode(), LcdMode::HBlank);
+        run_machine_cycles(&mut bus, 50);
+        assert_eq!(bus.lcd.ly, 1);
+        assert_eq!(bus.lcd.mode(), LcdMode::SearchingOam);
+    }
+
+    #[test]
+    fn vblank_interrupt_and_frame_length() {
+        let mut bus = bus();
+        run_machine_cycles(&mut bus, 144 * 114);
+        assert_eq!(bus.lcd.ly, 144);
+        assert_eq!(bus.lcd.mode(), LcdMode::VBlank);
+        assert_ne!(bus.int.requested() & Interrupt::VBlank.bit(), 0);
+
+        run_machine_cycles(&mut bus, 10 * 114);
+        assert_eq!(bus.lcd.ly, 0);
+        assert_eq!(bus.lcd.mode(), LcdMode::SearchingOam);
+    }
+
+    fn pixel(bus: &Bus, x: usize, y: usize) -> (u8, u8, u8) {
+        let pos_buf = (y * SCREEN_WIDTH + x) * 3;
+        let buffer = bus.ppu.frame_buffer();
+        (buffer[pos_buf], buffer[pos_buf + 1], buffer[pos_buf + 2])
+    }
+
+    #[test]
+    fn background_scrolling_and_palette() {
+        let mut bus = bus();
+        // tile 1 is solid color 1, tile 0 stays color 0
+        for row in 0..8 {
+            bus.write(0x8010 + row * 2, 0xFF);
+        }
+        // the second tile of the second map row uses tile 1
+        bus.write(0x9821, 1);
+        bus.lcd.update_palette(0b1110_0100, Palette::Background);
+        bus.lcd.scroll_x = 8;
+        bus.lcd.scroll_y = 8;
+
+        run_machine_cycles(&mut bus, 64);
+        assert_eq!(pixel(&bus, 0, 0), COLORS[1]);
+        assert_eq!(pixel(&bus, 8, 0), COLORS[0]);
+    }
+
+    #[test]
+    fn window_uses_its_own_line_counter() {
+        let mut bus = bus();
+        // tile 1 has a single color 3 row at the top
+        bus.write(0x8010, 0xFF);
+        bus.write(0x8011, 0xFF);
+        // the window map at 0x9C00 is filled with tile 1
+        for offset in 0..0x400 {
+            bus.write(0x9C00 + offset, 1);
+        }
+        bus.lcd.update_palette(0b1110_0100, Palette::Background);
+        bus.lcd.control |= 0b0110_0000;
+        bus.lcd.win_x = 7 + 80;
+        bus.lcd.win_y = 10;
+
+        run_machine_cycles(&mut bus, 10 * 114 + 64);
+        assert_eq!(pixel(&bus, 79, 10), COLORS[0]);
+        assert_eq!(pixel(&bus, 80, 10), COLORS[3]);
+
+        run_machine_cycles(&mut bus, 114);
+        assert_eq!(pixel(&bus, 80, 11), COLORS[0]);
+    }
+
+    fn write_sprite(bus: &mut Bus, index: u16, y: u8, x: u8, tile: u8, flags: u8) {
+        let address = 0xFE00 + index * 4;
+        bus.write(address, y);
+        bus.write(address + 1, x);
+        bus.write(address + 2, tile);
+        bus.write(address + 3, flags);
+    }
+
+    #[test]
+    fn sprites_priority_flip_and_transparency() {
+        let mut bus = bus();
+        bus.lcd.control |= 0b0000_0010;
+        bus.lcd.update_palette(0b1110_0100, Palette::Obj0);
+        bus.lcd.update_palette(0b1110_0100, Palette::Obj1);
+        // tile 1: left half color 1, right half transparent
+        bus.write(0x8010, 0xF0);
+        // tile 2: solid color 2
+        bus.write(0x8021, 0xFF);
+
+        // the left half of sprite 1 covers sprite 0, its right half is transparent
+        write_sprite(&mut bus, 0, 16, 12, 2, 0);
+        write_sprite(&mut bus, 1, 16, 8, 1, 0);
+        // sprite 2 is flipped horizontally
+        write_sprite(&mut bus, 2, 16, 40, 1, 0b0010_0000);
+
+        run_machine_cycles(&mut bus, 64);
+        assert_eq!(pixel(&bus, 0, 0), COLORS[1]);
+        assert_eq!(pixel(&bus, 4, 0), COLORS[2]);
+        assert_eq!(pixel(&bus, 32, 0), COLORS[0]);
+        assert_eq!(pixel(&bus, 39, 0), COLORS[1]);
+    }
+
+    #[test]
+    fn sprite_height_changed_after_oam_scan() {
+        let mut bus = bus();
+        bus.lcd.control |= 0b0000_0110;
+        bus.lcd.update_palette(0b1110_0100, Palette::Obj0);
+        // the bottom row of tile 2 is color 2
+        bus.write(0x802F, 0xFF);
+        // an 8x16 sprite flipped vertically, line 0 is its row 8
+        write_sprite(&mut bus, 0, 8, 8, 2, 0b0100_0000);
+
+        run_machine_cycles(&mut bus, 21);
+        bus.lcd.control &= !0b0000_0100;
+        run_machine_cycles(&mut bus, 43);
+        assert_eq!(pixel(&bus, 0, 0), COLORS[2]);
+    }
+
+    #[test]
+    fn sprite_limit_per_line() {
+        let mut bus = bus();
+        bus.lcd.control |= 0b0000_0010;
+        bus.lcd.update_palette(0b1110_0100, Palette::Obj0);
+        bus.write(0x8011, 0xFF);
+        for index in 0..11 {
+            write_sprite(&mut bus, index, 16, 8 + index as u8 * 8, 1, 0);
+        }
+
+        run_machine_cycles(&mut bus, 64);
+        assert_eq!(pixel(&bus, 72, 0), COLORS[2]);
+        assert_eq!(pixel(&bus, 80, 0), COLORS[0]);
+    }
+
+    #[test]
+    fn cgb_bg_attributes_and_palettes() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0143] = 0x80;
+        let mut bus = Bus::new(Cartridge::from_bytes(rom).unwrap());
+        bus.set_model(Model::Cgb);
+        assert!(bus.is_cgb_mode);
+
+        // tile 0 has color 1 in its leftmost pixel
+        bus.write(0x8000, 0x80);
+        // palette 2 with x flip for the first map entry
+        bus.write(0xFF4F, 1);
+        bus.write(0x9800, 0b0010_0010);
+        bus.write(0xFF4F, 0);
+        // color 1 of BG palette 2 is red
+        bus.write(0xFF68, 0x80 | (2 * 8 + 2));
+        bus.write(0xFF69, 0x1F);
+        bus.write(0xFF69, 0x00);
+
+        run_machine_cycles(&mut bus, 64);
+        assert_eq!(pixel(&bus, 0, 0), (0xFF, 0xFF, 0xFF));
+        assert_eq!(pixel(&bus, 7, 0), (0xFF, 0x00, 0x00));
+        assert_eq!(pixel(&bus, 8, 0), (0xFF, 0xFF, 0xFF));
+    }
+
+    #[test]
+    fn lyc_stat_interrupt() {
+        let mut bus = bus();
+        bus.lcd.ly_compare = 2;
+        bus.lcd.write_status(0b0100_0000);
+        run_machine_cycles(&mut bus, 2 * 114);
+        assert!(bus.lcd.lyc());
+        assert_ne!(bus.int.requested() & Interrupt::LcdStat.bit(), 0);
+
+        bus.int.set_requested(0);
+        run_machine_cycles(&mut bus, 1);
+        assert_eq!(bus.int.requested() & Interrupt::LcdStat.bit(), 0);
+    }
 }
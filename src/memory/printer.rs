@@ -0,0 +1,332 @@
+use super::ppu::{palette_shade, tile_color_id};
+use crate::util::png;
+use std::path::PathBuf;
+
+const PRINTER_WIDTH: usize = 160;
+const TILES_PER_ROW: usize = PRINTER_WIDTH / 8;
+const BYTES_PER_TILE_ROW: usize = TILES_PER_ROW * 16;
+// The printer's RAM holds 9 data packets of 640 bytes, 160x144 pixels
+const BUFFER_SIZE: usize = 9 * 2 * BYTES_PER_TILE_ROW;
+const GRAYS: [u8; 4] = [0xFF, 0xAA, 0x55, 0x00];
+
+const STATUS_CHECKSUM_ERROR: u8 = 1 << 0;
+const STATUS_BUSY: u8 = 1 << 1;
+const STATUS_FULL: u8 = 1 << 2;
+const STATUS_UNPROCESSED: u8 = 1 << 3;
+
+const COMMAND_INIT: u8 = 0x01;
+const COMMAND_PRINT: u8 = 0x02;
+const COMMAND_DATA: u8 = 0x04;
+const COMMAND_STATUS: u8 = 0x0F;
+
+/// Position in a packet: 0x88 0x33, command, compression, length (2),
+/// data, checksum (2), then the printer answers with 0x81 and its status
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PacketState {
+    Magic1,
+    Magic2,
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    Alive,
+    Status,
+}
+
+/// Game Boy Printer on the serial port, prints are written as PNG files
+#[derive(Debug)]
+pub struct Printer {
+    state: PacketState,
+    command: u8,
+    is_compressed: bool,
+    length: u16,
+    data: Vec<u8>,
+    checksum: u16, // sum of command, compression, length and data
+    received_checksum: u16,
+
+    status: u8,
+    busy_polls: u8,  // status requests the printer still reports busy
+    buffer: Vec<u8>, // tile data of the current image
+    sheet: Vec<u8>,  // grayscale pixels printed since the last bottom margin
+    output_dir: PathBuf,
+    prints: u32,
+}
+
+// Decodes the printer's run length encoding: a control byte with bit 7 set
+// repeats the next byte (control & 0x7F) + 2 times, otherwise
+// (control + 1) literal bytes follow
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(data.len() * 2);
+    let mut bytes = data.iter();
+    while let Some(control) = bytes.next() {
+        if control & 0x80 != 0 {
+            let Some(byte) = bytes.next() else {
+                break;
+            };
+            let count = (control & 0x7F) as usize + 2;
+            output.extend(std::iter::repeat_n(*byte, count));
+        } else {
+            output.extend(bytes.by_ref().take(*control as usize + 1));
+        }
+    }
+    output
+}
+
+impl Printer {
+    pub fn new(output_dir: PathBuf) -> Self {
+        Printer {
+            state: PacketState::Magic1,
+            command: 0,
+            is_compressed: false,
+            length: 0,
+            data: Vec::new(),
+            checksum: 0,
+            received_checksum: 0,
+
+            status: 0,
+            busy_polls: 0,
+            buffer: Vec::new(),
+            sheet: Vec::new(),
+            output_dir,
+            prints: 0,
+        }
+    }
+
+    /// Takes the byte sent by the Game Boy and returns the byte shifted back
+    pub fn exchange(&mut self, byte: u8) -> u8 {
+        let mut response = 0x00;
+        self.state = match self.state {
+            PacketState::Magic1 if byte == 0x88 => PacketState::Magic2,
+            PacketState::Magic1 => PacketState::Magic1,
+            PacketState::Magic2 if byte == 0x33 => PacketState::Command,
+            PacketState::Magic2 => PacketState::Magic1,
+            PacketState::Command => {
+                self.command = byte;
+                self.checksum = byte as u16;
+                PacketState::Compression
+            }
+            PacketState::Compression => {
+                self.is_compressed = byte & 1 != 0;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                PacketState::LengthLow
+            }
+            PacketState::LengthLow => {
+                self.length = byte as u16;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                PacketState::LengthHigh
+            }
+            PacketState::LengthHigh => {
+                self.length |= (byte as u16) << 8;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                self.data.clear();
+                if self.length == 0 {
+                    PacketState::ChecksumLow
+                } else {
+                    PacketState::Data
+                }
+            }
+            PacketState::Data => {
+                self.data.push(byte);
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                if self.data.len() == self.length as usize {
+                    PacketState::ChecksumLow
+                } else {
+                    PacketState::Data
+                }
+            }
+            PacketState::ChecksumLow => {
+                self.received_checksum = byte as u16;
+                PacketState::ChecksumHigh
+            }
+            PacketState::ChecksumHigh => {
+                self.received_checksum |= (byte as u16) << 8;
+                PacketState::Alive
+            }
+            PacketState::Alive => {
+                response = 0x81;
+                self.process_packet();
+                PacketState::Status
+            }
+            PacketState::Status => {
+                response = self.status;
+                if self.busy_polls > 0 {
+                    self.busy_polls -= 1;
+                    if self.busy_polls == 0 {
+                        self.status &= !STATUS_BUSY;
+                    }
+                }
+                PacketState::Magic1
+            }
+        };
+        response
+    }
+
+    fn process_packet(&mut self) {
+        if self.checksum != self.received_checksum {
+            self.status |= STATUS_CHECKSUM_ERROR;
+            return;
+        }
+        self.status &= !STATUS_CHECKSUM_ERROR;
+        match self.command {
+            COMMAND_INIT => {
+                self.buffer.clear();
+                self.status = 0;
+                self.busy_polls = 0;
+            }
+            COMMAND_DATA => {
+                let data = if self.is_compressed {
+                    decompress(&self.data)
+                } else {
+                    std::mem::take(&mut self.data)
+                };
+                let space = BUFFER_SIZE - self.buffer.len();
+                self.buffer.extend(data.iter().take(space));
+                if !self.buffer.is_empty() {
+                    self.status |= STATUS_UNPROCESSED;
+                }
+                if self.buffer.len() == BUFFER_SIZE {
+                    self.status |= STATUS_FULL;
+                }
+            }
+            COMMAND_PRINT if self.data.len() >= 4 => {
+                let margins = self.data[1];
+                let palette = self.data[2];
+                self.print(margins, palette);
+            }
+            COMMAND_STATUS => (),
+            _ => eprintln!("Unknown printer command {:#04X}", self.command),
+        }
+    }
+
+    fn print(&mut self, margins: u8, palette: u8) {
+        // a palette of 0 is treated like the default 0xE4 by the printer
+        let palette = if palette == 0 { 0xE4 } else { palette };
+        let margin_before = (margins >> 4) as usize;
+        let margin_after = (margins & 0x0F) as usize;
+
+        // margins are in units of 2 blank tile rows
+        self.sheet
+            .resize(self.sheet.len() + margin_before * 16 * PRINTER_WIDTH, 0xFF);
+        for tile_row in self.buffer.chunks_exact(BYTES_PER_TILE_ROW) {
+            for y in 0..8 {
+                for x in 0..PRINTER_WIDTH {
+                    let tile = (x / 8) * 16 + y * 2;
+                    let color_id = tile_color_id(tile_row[tile], tile_row[tile + 1], (x % 8) as u8);
+                    self.sheet
+                        .push(GRAYS[palette_shade(palette, color_id) as usize]);
+                }
+            }
+        }
+        self.sheet
+            .resize(self.sheet.len() + margin_after * 16 * PRINTER_WIDTH, 0xFF);
+
+        self.buffer.clear();
+        self.status &= !(STATUS_UNPROCESSED | STATUS_FULL);
+        self.status |= STATUS_BUSY;
+        self.busy_polls = 2;
+
+        // the paper is fed out after a bottom margin
+        if margin_after > 0 {
+            self.write_sheet();
+        }
+    }
+
+    fn write_sheet(&mut self) {
+        let height = self.sheet.len() / PRINTER_WIDTH;
+        if height == 0 {
+            return;
+        }
+        let path = loop {
+            self.prints += 1;
+            let path = self
+                .output_dir
+                .join(format!("print-{:03}.png", self.prints));
+            if !path.exists() {
+                break path;
+            }
+        };
+        match png::write_grayscale(&path, PRINTER_WIDTH, height, &self.sheet) {
+            Ok(()) => eprintln!("Printed {}", path.display()),
+            Err(error) => eprintln!("Could not write print: {}", error),
+        }
+        self.sheet.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn send_packet(printer: &mut Printer, command: u8, compression: u8, data: &[u8]) -> (u8, u8) {
+        let length = (data.len() as u16).to_le_bytes();
+        let mut packet = vec![0x88, 0x33, command, compression, length[0], length[1]];
+        packet.extend_from_slice(data);
+        let checksum = packet[2..]
+            .iter()
+            .fold(0u16, |sum, byte| sum.wrapping_add(*byte as u16));
+        packet.extend_from_slice(&checksum.to_le_bytes());
+        for byte in packet {
+            assert_eq!(printer.exchange(byte), 0x00);
+        }
+        (printer.exchange(0), printer.exchange(0))
+    }
+
+    #[test]
+    fn run_length_decoding() {
+        assert_eq!(
+            decompress(&[0x81, 0xAA, 0x01, 0x01, 0x02]),
+            vec![0xAA, 0xAA, 0xAA, 0x01, 0x02]
+        );
+    }
+
+    #[test]
+    fn print_to_png() {
+        let directory =
+            std::env::temp_dir().join(format!("gb-printer-test-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let mut printer = Printer::new(directory.clone());
+
+        assert_eq!(
+            send_packet(&mut printer, COMMAND_INIT, 0, &[]),
+            (0x81, 0x00)
+        );
+        // one band of black tiles, 640 0xFF bytes compressed to runs of 4 * 129 + 124
+        let mut compressed = Vec::new();
+        for _ in 0..4 {
+            compressed.extend_from_slice(&[0xFF, 0xFF]);
+        }
+        compressed.extend_from_slice(&[0xFA, 0xFF]);
+        assert_eq!(
+            send_packet(&mut printer, COMMAND_DATA, 1, &compressed),
+            (0x81, STATUS_UNPROCESSED)
+        );
+        assert_eq!(printer.buffer.len(), 2 * BYTES_PER_TILE_ROW);
+        send_packet(&mut printer, COMMAND_DATA, 0, &[]);
+
+        let (_, status) = send_packet(&mut printer, COMMAND_PRINT, 0, &[1, 0x01, 0xE4, 0x40]);
+        assert_eq!(status, STATUS_BUSY);
+        assert_eq!(
+            send_packet(&mut printer, COMMAND_STATUS, 0, &[]).1,
+            STATUS_BUSY
+        );
+        assert_eq!(send_packet(&mut printer, COMMAND_STATUS, 0, &[]).1, 0);
+
+        let png = std::fs::read(directory.join("print-001.png")).unwrap();
+        // 16 rows of the image and 16 rows of bottom margin
+        assert_eq!(png[16..24], [0, 0, 0, 160, 0, 0, 0, 32]);
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn checksum_error() {
+        let mut printer = Printer::new(PathBuf::new());
+        for byte in [0x88, 0x33, COMMAND_STATUS, 0, 0, 0, 0x10, 0x00] {
+            printer.exchange(byte);
+        }
+        assert_eq!(printer.exchange(0), 0x81);
+        assert_eq!(printer.exchange(0), STATUS_CHECKSUM_ERROR);
+    }
+}
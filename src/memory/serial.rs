@@ -0,0 +1,238 @@
+use super::printer::Printer;
+use crate::util::state::{Snapshot, StateReader, StateWriter};
+
+// 8192 Hz, 512 cycles per bit with the internal clock
+const MACHINE_CYCLES_PER_BIT: u16 = 128;
+
+/// Something connected to the link port. Gets the byte sent by the
+/// Game Boy and returns the byte shifted in at the same time.
+pub type SerialDevice = Box<dyn FnMut(u8) -> u8>;
+
+/// The other Game Boy on a link cable as seen at the last sync
+#[derive(Debug, Default)]
+pub struct LinkPeer {
+    pub data: u8,         // its SB
+    pub is_waiting: bool, // it waits for our clock (external clock transfer)
+    pub sent: Vec<u8>,    // bytes we sent with our clock since the last sync
+}
+
+impl LinkPeer {
+    fn exchange(&mut self, byte: u8) -> u8 {
+        self.sent.push(byte);
+        if self.is_waiting {
+            self.is_waiting = false;
+            self.data
+        } else {
+            0xFF
+        }
+    }
+}
+
+enum Connection {
+    None,
+    Device(SerialDevice),
+    Link(LinkPeer),
+    Printer(Printer),
+}
+
+/// Serial transfer data (SB) and control (SC)
+pub struct Serial {
+    data: u8,    // SB
+    control: u8, // SC: bit 7 transfer in progress, bit 0 internal clock
+    bits_left: u8,
+    cycles: u16, // machine cycles of the current bit
+    connection: Connection,
+}
+
+impl Serial {
+    pub fn new() -> Self {
+        Serial {
+            data: 0,
+            control: 0,
+            bits_left: 0,
+            cycles: 0,
+            connection: Connection::None,
+        }
+    }
+
+    pub fn connect(&mut self, device: SerialDevice) {
+        self.connection = Connection::Device(device);
+    }
+
+    pub fn connect_link(&mut self) {
+        self.connection = Connection::Link(LinkPeer::default());
+    }
+
+    pub fn connect_printer(&mut self, printer: Printer) {
+        self.connection = Connection::Printer(printer);
+    }
+
+    pub fn disconnect(&mut self) {
+        self.connection = Connection::None;
+    }
+
+    pub fn link_peer(&mut self) -> Option<&mut LinkPeer> {
+        match &mut self.connection {
+            Connection::Link(peer) => Some(peer),
+            _ => None,
+        }
+    }
+
+    pub fn data(&self) -> u8 {
+        self.data
+    }
+
+    pub fn set_data(&mut self, data: u8) {
+        self.data = data;
+    }
+
+    pub fn control(&self) -> u8 {
+        // the unused bits read as 1
+        self.control | 0b0111_1110
+    }
+
+    pub fn set_control(&mut self, data: u8) {
+        self.control = data & 0b1000_0001;
+        if self.is_transferring() {
+            self.bits_left = 8;
+            self.cycles = 0;
+        }
+    }
+
+    fn is_transferring(&self) -> bool {
+        self.control & 0x80 != 0
+    }
+
+    fn is_internal_clock(&self) -> bool {
+        self.control & 0x01 != 0
+    }
+
+    /// A transfer was started with the external clock
+    pub fn is_waiting_for_clock(&self) -> bool {
+        self.is_transferring() && !self.is_internal_clock()
+    }
+
+    /// The other side clocked out `byte`, returns whether the serial
+    /// interrupt should be requested
+    pub fn clock_external(&mut self, byte: u8) -> bool {
+        if !self.is_waiting_for_clock() {
+            return false;
+        }
+        self.data = byte;
+        self.control &= !0x80;
+        true
+    }
+
+    // One machine cycle, returns whether the serial interrupt should be requested
+    pub fn tick(&mut self) -> bool {
+        // with the external clock the transfer waits for the other side
+        if !self.is_transferring() || !self.is_internal_clock() {
+            return false;
+        }
+        self.cycles += 1;
+        if self.cycles < MACHINE_CYCLES_PER_BIT {
+            return false;
+        }
+        self.cycles = 0;
+        self.bits_left -= 1;
+        if self.bits_left > 0 {
+            return false;
+        }
+
+        // without a device connected 0xFF is shifted in
+        self.data = match &mut self.connection {
+            Connection::None => 0xFF,
+            Connection::Device(device) => device(self.data),
+            Connection::Link(peer) => peer.exchange(self.data),
+            Connection::Printer(printer) => printer.exchange(self.data),
+        };
+        self.control &= !0x80;
+        true
+    }
+}
+
+// The connected device is set up by the frontend and is not part of the state
+impl Snapshot for Serial {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.data);
+        state.u8(self.control);
+        state.u8(self.bits_left);
+        state.u16(self.cycles);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.data = state.u8()?;
+        self.control = state.u8()?;
+        self.bits_left = state.u8()?.min(8);
+        self.cycles = state.u16()?.min(MACHINE_CYCLES_PER_BIT - 1);
+        // a transfer without bits left would underflow
+        if self.bits_left == 0 {
+            self.control &= !0x80;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn internal_clock_transfer() {
+        let sent = Rc::new(RefCell::new(Vec::new()));
+        let device_sent = sent.clone();
+        let mut serial = Serial::new();
+        serial.connect(Box::new(move |byte| {
+            device_sent.borrow_mut().push(byte);
+            0x42
+        }));
+
+        serial.set_data(b'A');
+        serial.set_control(0x81);
+        assert_eq!(serial.control(), 0xFF);
+        for _ in 0..8 * MACHINE_CYCLES_PER_BIT - 1 {
+            assert!(!serial.tick());
+        }
+        assert!(serial.tick());
+        assert_eq!(*sent.borrow(), vec![b'A']);
+        assert_eq!(serial.data(), 0x42);
+        assert_eq!(serial.control(), 0x7F);
+    }
+
+    #[test]
+    fn external_clock_waits() {
+        let mut serial = Serial::new();
+        serial.set_control(0x80);
+        for _ in 0..8 * MACHINE_CYCLES_PER_BIT {
+            assert!(!serial.tick());
+        }
+        assert_eq!(serial.control(), 0xFE);
+
+        serial.set_data(0x12);
+        assert!(serial.clock_external(0x34));
+        assert_eq!(serial.data(), 0x34);
+        assert_eq!(serial.control(), 0x7E);
+        assert!(!serial.clock_external(0x56));
+    }
+
+    #[test]
+    fn invalid_state_is_clamped() {
+        let mut writer = StateWriter::new();
+        writer.u8(0x12);
+        writer.u8(0x81);
+        writer.u8(0);
+        writer.u16(u16::MAX);
+        let data = writer.into_bytes();
+
+        let mut serial = Serial::new();
+        serial
+            .load_state(&mut StateReader::new(&data).unwrap())
+            .unwrap();
+        // the transfer is stopped
+        assert_eq!(serial.control(), 0x7F);
+        assert!(!serial.tick());
+        assert_eq!(serial.data(), 0x12);
+    }
+}
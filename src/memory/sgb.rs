@@ -0,0 +1,724 @@
+use super::bus::Bus;
+use super::palette::rgb555_to_rgb888;
+use super::ppu::{Ppu, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::util::state::{Snapshot, StateReader, StateWriter};
+
+/// The SNES picture with the Game Boy screen in the middle of the border
+pub const SGB_SCREEN_WIDTH: usize = 256;
+pub const SGB_SCREEN_HEIGHT: usize = 224;
+const SCREEN_X: usize = (SGB_SCREEN_WIDTH - SCREEN_WIDTH) / 2;
+const SCREEN_Y: usize = (SGB_SCREEN_HEIGHT - SCREEN_HEIGHT) / 2;
+
+// the attribute map assigns one of the 4 palettes to each 8x8 tile of the screen
+const ATTRIBUTE_WIDTH: usize = SCREEN_WIDTH / 8;
+const ATTRIBUTE_HEIGHT: usize = SCREEN_HEIGHT / 8;
+
+const PACKET_SIZE: usize = 16;
+// CHR_TRN and PCT_TRN copy 4 KiB from what the Game Boy displays
+const TRANSFER_SIZE: usize = 0x1000;
+// 256 SNES tiles of 8x8 pixels with 4 bits per pixel
+const BORDER_TILE_SIZE: usize = 32;
+const BORDER_MAP_WIDTH: usize = SGB_SCREEN_WIDTH / 8;
+const BORDER_MAP_SIZE: usize = BORDER_MAP_WIDTH * (SGB_SCREEN_HEIGHT / 8) * 2;
+// PCT_TRN has palettes 4-7 after the map and some unused space
+const BORDER_PALETTES_OFFSET: usize = 0x800;
+const BORDER_PALETTES_SIZE: usize = 4 * 16 * 2;
+// PAL_TRN sends 512 palettes of 4 colors, ATTR_TRN 45 attribute maps
+// with the palettes of 4 tiles per byte
+const SYSTEM_PALETTE_COUNT: usize = 512;
+const ATTRIBUTE_FILE_SIZE: usize = ATTRIBUTE_WIDTH * ATTRIBUTE_HEIGHT / 4;
+const ATTRIBUTE_FILE_COUNT: usize = 45;
+
+const COMMAND_PAL01: u8 = 0x00;
+const COMMAND_PAL23: u8 = 0x01;
+const COMMAND_PAL03: u8 = 0x02;
+const COMMAND_PAL12: u8 = 0x03;
+const COMMAND_ATTR_BLK: u8 = 0x04;
+const COMMAND_ATTR_LIN: u8 = 0x05;
+const COMMAND_ATTR_DIV: u8 = 0x06;
+const COMMAND_ATTR_CHR: u8 = 0x07;
+const COMMAND_PAL_SET: u8 = 0x0A;
+const COMMAND_PAL_TRN: u8 = 0x0B;
+const COMMAND_MLT_REQ: u8 = 0x11;
+const COMMAND_CHR_TRN: u8 = 0x13;
+const COMMAND_PCT_TRN: u8 = 0x14;
+const COMMAND_ATTR_TRN: u8 = 0x15;
+const COMMAND_ATTR_SET: u8 = 0x16;
+const COMMAND_MASK_EN: u8 = 0x17;
+
+// the SGB's default palette 1-A
+const DEFAULT_PALETTE: [u16; 4] = [0x67BF, 0x265B, 0x10B5, 0x2866];
+
+/// What MASK_EN does to the Game Boy screen
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Mask {
+    None,
+    Freeze, // keeps showing the last picture
+    Black,
+    Color0, // filled with color 0
+}
+
+/// A VRAM transfer waiting for the next VBlank
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Transfer {
+    BorderTiles(usize), // CHR_TRN, the first or second 128 tiles
+    Border,             // PCT_TRN, the tile map and palettes 4-7
+    SystemPalettes,     // PAL_TRN
+    AttributeFiles,     // ATTR_TRN
+}
+
+/// Super Game Boy: receives command packets through P1 and colors the
+/// DMG picture with its palettes and attribute map inside a 256x224 border
+#[derive(Debug)]
+pub struct Sgb {
+    packet: [u8; PACKET_SIZE],
+    packet_bits: usize, // bits received of the current packet
+    is_receiving: bool, // a reset pulse started a packet
+    command: Vec<u8>,   // packets of the current command
+    select: u8,         // the last P14 and P15 written
+    player_count: u8,   // MLT_REQ
+    player: u8,
+
+    palettes: [[u16; 4]; 4],
+    attributes: [u8; ATTRIBUTE_WIDTH * ATTRIBUTE_HEIGHT],
+    system_palettes: Vec<[u16; 4]>, // picked from by PAL_SET
+    attribute_files: Vec<u8>,       // picked from by PAL_SET and ATTR_SET
+    mask: Mask,
+    transfer: Option<Transfer>,
+    border_tiles: Vec<u8>,
+    border_map: Vec<u8>,             // 32x28 little endian entries
+    border_palettes: [[u16; 16]; 4], // palettes 4-7
+    screen: Vec<u8>,
+}
+
+impl Sgb {
+    pub fn new() -> Self {
+        Sgb {
+            packet: [0; PACKET_SIZE],
+            packet_bits: 0,
+            is_receiving: false,
+            command: Vec::new(),
+            select: 0x30,
+            player_count: 1,
+            player: 0,
+
+            palettes: [DEFAULT_PALETTE; 4],
+            attributes: [0; ATTRIBUTE_WIDTH * ATTRIBUTE_HEIGHT],
+            system_palettes: vec![[0; 4]; SYSTEM_PALETTE_COUNT],
+            attribute_files: vec![0; ATTRIBUTE_FILE_COUNT * ATTRIBUTE_FILE_SIZE],
+            mask: Mask::None,
+            transfer: None,
+            border_tiles: vec![0; 256 * BORDER_TILE_SIZE],
+            border_map: vec![0; BORDER_MAP_SIZE],
+            border_palettes: [[0; 16]; 4],
+            screen: vec![0; SGB_SCREEN_WIDTH * SGB_SCREEN_HEIGHT * 3],
+        }
+    }
+
+    /// The 256x224 picture as RGB24
+    pub fn screen(&self) -> &[u8] {
+        &self.screen
+    }
+
+    /// The selected joypad while more than one is enabled by MLT_REQ
+    pub fn joypad_id(&self) -> Option<u8> {
+        (self.player_count > 1).then_some(self.player)
+    }
+
+    /// Packets are sent one bit per write to P1: both lines low resets,
+    /// P14 low is a 0 and P15 low a 1, both lines go high between bits
+    pub fn write_joypad(&mut self, data: u8) {
+        let select = data & 0x30;
+        // P15 going high selects the next joypad
+        if self.player_count > 1 && self.select & 0x20 == 0 && select & 0x20 != 0 {
+            self.player = (self.player + 1) % self.player_count;
+        }
+        let is_pulse = self.select == 0x30;
+        self.select = select;
+        match select {
+            0x00 => {
+                self.is_receiving = true;
+                self.packet_bits = 0;
+                self.packet = [0; PACKET_SIZE];
+            }
+            0x10 | 0x20 if is_pulse && self.is_receiving => {
+                if select == 0x10 {
+                    self.packet[self.packet_bits / 8] |= 1 << (self.packet_bits % 8);
+                }
+                self.packet_bits += 1;
+                // the stop bit that follows is ignored
+                if self.packet_bits == PACKET_SIZE * 8 {
+                    self.is_receiving = false;
+                    self.receive_packet();
+                }
+            }
+            _ => (),
+        }
+    }
+
+    // The first byte is the command times 8 plus the number of packets
+    fn receive_packet(&mut self) {
+        self.command.extend_from_slice(&self.packet);
+        let packets = (self.command[0] & 0b111).max(1) as usize;
+        if self.command.len() >= packets * PACKET_SIZE {
+            let command = std::mem::take(&mut self.command);
+            self.execute(&command);
+        }
+    }
+
+    fn execute(&mut self, data: &[u8]) {
+        match data[0] >> 3 {
+            COMMAND_PAL01 => self.set_palettes(0, 1, data),
+            COMMAND_PAL23 => self.set_palettes(2, 3, data),
+            COMMAND_PAL03 => self.set_palettes(0, 3, data),
+            COMMAND_PAL12 => self.set_palettes(1, 2, data),
+            COMMAND_ATTR_BLK => self.attribute_blocks(data),
+            COMMAND_ATTR_LIN => self.attribute_lines(data),
+            COMMAND_ATTR_DIV => self.attribute_division(data),
+            COMMAND_ATTR_CHR => self.attribute_characters(data),
+            COMMAND_PAL_SET => {
+                let palette = |index: usize| {
+                    let number = u16::from_le_bytes([data[1 + index * 2], data[2 + index * 2]]);
+                    self.system_palettes[number as usize & (SYSTEM_PALETTE_COUNT - 1)]
+                };
+                let palettes = [palette(0), palette(1), palette(2), palette(3)];
+                self.palettes = palettes;
+                // color 0 of the first palette is shared by all
+                for palette in self.palettes.iter_mut() {
+                    palette[0] = palettes[0][0];
+                }
+                if data[9] & 0x80 != 0 {
+                    self.set_attribute_file(data[9]);
+                }
+                if data[9] & 0x40 != 0 {
+                    self.mask = Mask::None;
+                }
+            }
+            COMMAND_PAL_TRN => self.transfer = Some(Transfer::SystemPalettes),
+            COMMAND_MLT_REQ => {
+                self.player_count = match data[1] & 0b11 {
+                    1 => 2,
+                    3 => 4,
+                    _ => 1,
+                };
+                self.player = 0;
+            }
+            COMMAND_CHR_TRN => {
+                self.transfer = Some(Transfer::BorderTiles((data[1] & 1) as usize));
+            }
+            COMMAND_PCT_TRN => self.transfer = Some(Transfer::Border),
+            COMMAND_ATTR_TRN => self.transfer = Some(Transfer::AttributeFiles),
+            COMMAND_ATTR_SET => {
+                self.set_attribute_file(data[1]);
+                if data[1] & 0x40 != 0 {
+                    self.mask = Mask::None;
+                }
+            }
+            COMMAND_MASK_EN => {
+                self.mask = match data[1] & 0b11 {
+                    0 => Mask::None,
+                    1 => Mask::Freeze,
+                    2 => Mask::Black,
+                    _ => Mask::Color0,
+                }
+            }
+            // sound, SNES code and the other commands don't change the picture
+            _ => (),
+        }
+    }
+
+    // Bits 0-5 select one of the files sent by ATTR_TRN
+    fn set_attribute_file(&mut self, file: u8) {
+        let file = (file & 0x3F) as usize;
+        if file >= ATTRIBUTE_FILE_COUNT {
+            return;
+        }
+        let start = file * ATTRIBUTE_FILE_SIZE;
+        let bytes = self.attribute_files[start..start + ATTRIBUTE_FILE_SIZE].to_vec();
+        for (index, attribute) in self.attributes.iter_mut().enumerate() {
+            *attribute = (bytes[index / 4] >> (6 - (index % 4) * 2)) & 0b11;
+        }
+    }
+
+    // Color 0 is shared by all palettes, then colors 1-3 of both palettes
+    fn set_palettes(&mut self, first: usize, second: usize, data: &[u8]) {
+        let color = |index: usize| u16::from_le_bytes([data[1 + index * 2], data[2 + index * 2]]);
+        for palette in self.palettes.iter_mut() {
+            palette[0] = color(0);
+        }
+        for id in 1..4 {
+            self.palettes[first][id] = color(id);
+            self.palettes[second][id] = color(id + 3);
+        }
+    }
+
+    fn set_attribute(&mut self, x: usize, y: usize, palette: u8) {
+        self.attributes[y * ATTRIBUTE_WIDTH + x] = palette & 0b11;
+    }
+
+    // Data sets of control, palettes and the corners of a rectangle in tiles
+    fn attribute_blocks(&mut self, data: &[u8]) {
+        let count = data[1] as usize;
+        for block in data[2..].chunks_exact(6).take(count) {
+            let control = block[0] & 0b111;
+            let inside = block[1] & 0b11;
+            let mut border = (block[1] >> 2) & 0b11;
+            let outside = (block[1] >> 4) & 0b11;
+            // with only the inside or outside set the border gets the same palette
+            match control {
+                0b001 => border = inside,
+                0b100 => border = outside,
+                _ => (),
+            }
+            let paints_border = control & 0b010 != 0 || control == 0b001 || control == 0b100;
+            let (left, top, right, bottom) = (block[2], block[3], block[4], block[5]);
+            for y in 0..ATTRIBUTE_HEIGHT {
+                for x in 0..ATTRIBUTE_WIDTH {
+                    let (tile_x, tile_y) = (x as u8, y as u8);
+                    let is_within =
+                        (left..=right).contains(&tile_x) && (top..=bottom).contains(&tile_y);
+                    let is_inside =
+                        tile_x > left && tile_x < right && tile_y > top && tile_y < bottom;
+                    if is_inside && control & 0b001 != 0 {
+                        self.set_attribute(x, y, inside);
+                    } else if is_within && !is_inside && paints_border {
+                        self.set_attribute(x, y, border);
+                    } else if !is_within && control & 0b100 != 0 {
+                        self.set_attribute(x, y, outside);
+                    }
+                }
+            }
+        }
+    }
+
+    // One byte per line: bits 0-4 the line, 5-6 the palette, 7 set for a row
+    fn attribute_lines(&mut self, data: &[u8]) {
+        let count = data[1] as usize;
+        for line in data[2..].iter().take(count) {
+            let number = (line & 0x1F) as usize;
+            let palette = (line >> 5) & 0b11;
+            if line & 0x80 != 0 {
+                if number < ATTRIBUTE_HEIGHT {
+                    (0..ATTRIBUTE_WIDTH).for_each(|x| self.set_attribute(x, number, palette));
+                }
+            } else if number < ATTRIBUTE_WIDTH {
+                (0..ATTRIBUTE_HEIGHT).for_each(|y| self.set_attribute(number, y, palette));
+            }
+        }
+    }
+
+    // Splits the screen at a row or column, the line itself gets its own palette
+    fn attribute_division(&mut self, data: &[u8]) {
+        let after = data[1] & 0b11;
+        let before = (data[1] >> 2) & 0b11;
+        let on_line = (data[1] >> 4) & 0b11;
+        let is_horizontal = data[1] & 0x40 != 0;
+        let position = data[2] as usize;
+        for y in 0..ATTRIBUTE_HEIGHT {
+            for x in 0..ATTRIBUTE_WIDTH {
+                let coordinate = if is_horizontal { y } else { x };
+                let palette = match coordinate.cmp(&position) {
+                    std::cmp::Ordering::Less => before,
+                    std::cmp::Ordering::Equal => on_line,
+                    std::cmp::Ordering::Greater => after,
+                };
+                self.set_attribute(x, y, palette);
+            }
+        }
+    }
+
+    // Palettes of consecutive tiles packed 4 per byte, row or column wise
+    fn attribute_characters(&mut self, data: &[u8]) {
+        let (mut x, mut y) = (data[1] as usize, data[2] as usize);
+        let count = u16::from_le_bytes([data[3], data[4]]) as usize;
+        let is_vertical = data[5] & 1 != 0;
+        for index in 0..count {
+            let Some(byte) = data.get(6 + index / 4) else {
+                break;
+            };
+            if x >= ATTRIBUTE_WIDTH || y >= ATTRIBUTE_HEIGHT {
+                break;
+            }
+            self.set_attribute(x, y, byte >> (6 - (index % 4) * 2));
+            if is_vertical {
+                y += 1;
+                if y == ATTRIBUTE_HEIGHT {
+                    (x, y) = (x + 1, 0);
+                }
+            } else {
+                x += 1;
+                if x == ATTRIBUTE_WIDTH {
+                    (x, y) = (0, y + 1);
+                }
+            }
+        }
+    }
+
+    // The SNES reads the tiles shown by the BG map, 20 per row
+    fn read_transfer(bus: &Bus) -> Vec<u8> {
+        let mut data = Vec::with_capacity(TRANSFER_SIZE);
+        for tile in 0..(TRANSFER_SIZE / 16) as u16 {
+            let map_address = bus.lcd.bg_map_area() + (tile / 20) * 32 + tile % 20;
+            let tile_address = Ppu::bgw_tile_address(&bus.lcd, bus.read_vram(0, map_address));
+            data.extend((0..16).map(|offset| bus.read_vram(0, tile_address + offset)));
+        }
+        data
+    }
+
+    /// Called by the PPU when it enters VBlank, does pending transfers and renders the picture
+    pub fn vblank(bus: &mut Bus) {
+        if let Some(transfer) = bus.sgb.transfer.take() {
+            let data = Self::read_transfer(bus);
+            let sgb = &mut bus.sgb;
+            match transfer {
+                Transfer::BorderTiles(half) => {
+                    let start = half * TRANSFER_SIZE;
+                    sgb.border_tiles[start..start + TRANSFER_SIZE].copy_from_slice(&data);
+                }
+                Transfer::Border => {
+                    sgb.border_map.copy_from_slice(&data[..BORDER_MAP_SIZE]);
+                    let colors = &data
+                        [BORDER_PALETTES_OFFSET..BORDER_PALETTES_OFFSET + BORDER_PALETTES_SIZE];
+                    for (index, color) in colors.chunks_exact(2).enumerate() {
+                        sgb.border_palettes[index / 16][index % 16] =
+                            u16::from_le_bytes([color[0], color[1]]);
+                    }
+                }
+                Transfer::SystemPalettes => {
+                    for (index, color) in data.chunks_exact(2).enumerate() {
+                        sgb.system_palettes[index / 4][index % 4] =
+                            u16::from_le_bytes([color[0], color[1]]);
+                    }
+                }
+                Transfer::AttributeFiles => {
+                    let size = sgb.attribute_files.len();
+                    sgb.attribute_files.copy_from_slice(&data[..size]);
+                }
+            }
+        }
+        let shades = bus.ppu.shades();
+        bus.sgb.render(shades);
+    }
+
+    // Color id of a border pixel, 0 is transparent
+    fn border_pixel(&self, x: usize, y: usize) -> Option<u16> {
+        let offset = ((y / 8) * BORDER_MAP_WIDTH + x / 8) * 2;
+        let entry = u16::from_le_bytes([self.border_map[offset], self.border_map[offset + 1]]);
+        // bits 0-7 tile, 10-12 palette, 14 x flip, 15 y flip
+        let tile = (entry & 0xFF) as usize;
+        let palette = ((entry >> 10) & 0b11) as usize;
+        let column = if entry & 0x4000 != 0 {
+            7 - x % 8
+        } else {
+            x % 8
+        };
+        let row = if entry & 0x8000 != 0 {
+            7 - y % 8
+        } else {
+            y % 8
+        };
+        // SNES 4bpp tiles: bit planes 0 and 1 for all rows, then planes 2 and 3
+        let address = tile * BORDER_TILE_SIZE + row * 2;
+        let planes = [
+            self.border_tiles[address],
+            self.border_tiles[address + 1],
+            self.border_tiles[address + 16],
+            self.border_tiles[address + 17],
+        ];
+        let color_id = planes.iter().enumerate().fold(0, |id, (plane, bits)| {
+            id | ((bits >> (7 - column)) & 1) << plane
+        });
+        (color_id != 0).then(|| self.border_palettes[palette][color_id as usize])
+    }
+
+    /// Draws the border and the colored Game Boy screen
+    pub fn render(&mut self, shades: &[u8]) {
+        let backdrop = self.palettes[0][0];
+        for y in 0..SGB_SCREEN_HEIGHT {
+            for x in 0..SGB_SCREEN_WIDTH {
+                let is_screen = (SCREEN_X..SCREEN_X + SCREEN_WIDTH).contains(&x)
+                    && (SCREEN_Y..SCREEN_Y + SCREEN_HEIGHT).contains(&y);
+                let color = if let Some(color) = self.border_pixel(x, y) {
+                    color
+                } else if !is_screen {
+                    backdrop
+                } else {
+                    let (screen_x, screen_y) = (x - SCREEN_X, y - SCREEN_Y);
+                    match self.mask {
+                        Mask::Freeze => continue,
+                        Mask::Black => 0,
+                        Mask::Color0 => backdrop,
+                        Mask::None => {
+                            let attribute = (screen_y / 8) * ATTRIBUTE_WIDTH + screen_x / 8;
+                            let palette = self.palettes[self.attributes[attribute] as usize];
+                            palette[shades[screen_y * SCREEN_WIDTH + screen_x] as usize]
+                        }
+                    }
+                };
+                let index = (y * SGB_SCREEN_WIDTH + x) * 3;
+                (
+                    self.screen[index],
+                    self.screen[index + 1],
+                    self.screen[index + 2],
+                ) = rgb555_to_rgb888(color);
+            }
+        }
+    }
+}
+
+// The rendered picture is not saved, it is redrawn at the next VBlank
+impl Snapshot for Sgb {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.packet);
+        state.u8(self.packet_bits as u8);
+        state.bool(self.is_receiving);
+        state.bytes(&self.command);
+        state.u8(self.select);
+        state.u8(self.player_count);
+        state.u8(self.player);
+
+        for color in self.palettes.iter().flatten() {
+            state.u16(*color);
+        }
+        state.bytes(&self.attributes);
+        state.u8(self.mask as u8);
+        state.u8(match self.transfer {
+            None => 0,
+            Some(Transfer::BorderTiles(half)) => 1 + half as u8,
+            Some(Transfer::Border) => 3,
+            Some(Transfer::SystemPalettes) => 4,
+            Some(Transfer::AttributeFiles) => 5,
+        });
+        state.bytes(&self.border_tiles);
+        state.bytes(&self.border_map);
+        for color in self.border_palettes.iter().flatten() {
+            state.u16(*color);
+        }
+        for color in self.system_palettes.iter().flatten() {
+            state.u16(*color);
+        }
+        state.bytes(&self.attribute_files);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        state.bytes_into(&mut self.packet)?;
+        self.packet_bits = (state.u8()? as usize).min(PACKET_SIZE * 8 - 1);
+        self.is_receiving = state.bool()?;
+        self.command = state.bytes()?;
+        self.command.truncate(7 * PACKET_SIZE);
+        self.select = state.u8()?;
+        self.player_count = state.u8()?.clamp(1, 4);
+        self.player = state.u8()? % self.player_count;
+
+        for color in self.palettes.iter_mut().flatten() {
+            *color = state.u16()?;
+        }
+        state.bytes_into(&mut self.attributes)?;
+        self.attributes
+            .iter_mut()
+            .for_each(|palette| *palette &= 0b11);
+        self.mask = match state.u8()? {
+            0 => Mask::None,
+            1 => Mask::Freeze,
+            2 => Mask::Black,
+            _ => Mask::Color0,
+        };
+        self.transfer = match state.u8()? {
+            1 => Some(Transfer::BorderTiles(0)),
+            2 => Some(Transfer::BorderTiles(1)),
+            3 => Some(Transfer::Border),
+            4 => Some(Transfer::SystemPalettes),
+            5 => Some(Transfer::AttributeFiles),
+            _ => None,
+        };
+        state.bytes_into(&mut self.border_tiles)?;
+        state.bytes_into(&mut self.border_map)?;
+        for color in self.border_palettes.iter_mut().flatten() {
+            *color = state.u16()?;
+        }
+        // PAL_TRN and ATTR_TRN data was added in version 2
+        if state.version() >= 2 {
+            for color in self.system_palettes.iter_mut().flatten() {
+                *color = state.u16()?;
+            }
+            state.bytes_into(&mut self.attribute_files)?;
+        } else {
+            self.system_palettes.fill([0; 4]);
+            self.attribute_files.fill(0);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::cartridge::Cartridge;
+    use crate::model::Model;
+
+    fn sgb_bus() -> Bus {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0146] = 0x03;
+        rom[0x014B] = 0x33;
+        let mut bus = Bus::new(Cartridge::from_bytes(rom).unwrap());
+        bus.set_model(Model::Sgb);
+        assert!(bus.is_sgb_mode);
+        bus
+    }
+
+    fn send_packet(bus: &mut Bus, packet: &[u8]) {
+        bus.write(0xFF00, 0x00);
+        bus.write(0xFF00, 0x30);
+        for index in 0..PACKET_SIZE * 8 {
+            let byte = packet.get(index / 8).copied().unwrap_or(0);
+            let bit = (byte >> (index % 8)) & 1;
+            bus.write(0xFF00, if bit == 1 { 0x10 } else { 0x20 });
+            bus.write(0xFF00, 0x30);
+        }
+        // stop bit
+        bus.write(0xFF00, 0x20);
+        bus.write(0xFF00, 0x30);
+    }
+
+    fn pixel(bus: &Bus, x: usize, y: usize) -> (u8, u8, u8) {
+        let index = (y * SGB_SCREEN_WIDTH + x) * 3;
+        let screen = bus.sgb.screen();
+        (screen[index], screen[index + 1], screen[index + 2])
+    }
+
+    #[test]
+    fn multiplayer_request() {
+        let mut bus = sgb_bus();
+        assert_eq!(bus.read(0xFF00) & 0x0F, 0x0F);
+        send_packet(&mut bus, &[COMMAND_MLT_REQ << 3 | 1, 1]);
+        bus.write(0xFF00, 0x10);
+        bus.write(0xFF00, 0x30);
+        assert_eq!(bus.read(0xFF00) & 0x0F, 0x0E);
+        bus.write(0xFF00, 0x10);
+        bus.write(0xFF00, 0x30);
+        assert_eq!(bus.read(0xFF00) & 0x0F, 0x0F);
+    }
+
+    #[test]
+    fn palettes_and_attribute_blocks() {
+        let mut bus = sgb_bus();
+        // color 0 red, palette 1 color 1 blue
+        let mut packet = vec![COMMAND_PAL01 << 3 | 1, 0x1F, 0x00];
+        packet.extend_from_slice(&[0; 6]);
+        packet.extend_from_slice(&[0x00, 0x7C]);
+        send_packet(&mut bus, &packet);
+        // palette 1 inside the tiles (1, 1) to (3, 3)
+        send_packet(
+            &mut bus,
+            &[COMMAND_ATTR_BLK << 3 | 1, 1, 0b001, 0b01, 0, 0, 4, 4],
+        );
+        assert_eq!(bus.sgb.attributes[ATTRIBUTE_WIDTH + 1], 1);
+        assert_eq!(bus.sgb.attributes[ATTRIBUTE_WIDTH * 3 + 3], 1);
+        // only the inside is set so the border gets the same palette
+        assert_eq!(bus.sgb.attributes[ATTRIBUTE_WIDTH * 4 + 4], 1);
+        assert_eq!(bus.sgb.attributes[ATTRIBUTE_WIDTH * 5 + 5], 0);
+
+        // tile 1 row 0 is color 1, BGP maps it to shade 1
+        bus.write(0xFF47, 0xE4);
+        bus.write(0x8010, 0xFF);
+        bus.write(0x9800 + 32 + 1, 1);
+        for _ in 0..154 * 114 {
+            Ppu::tick(&mut bus);
+        }
+        assert_eq!(pixel(&bus, 0, 0), (0xFF, 0x00, 0x00));
+        assert_eq!(pixel(&bus, SCREEN_X + 8, SCREEN_Y + 8), (0x00, 0x00, 0xFF));
+        assert_eq!(pixel(&bus, SCREEN_X + 8, SCREEN_Y + 9), (0xFF, 0x00, 0x00));
+
+        send_packet(&mut bus, &[COMMAND_MASK_EN << 3 | 1, 2]);
+        for _ in 0..154 * 114 {
+            Ppu::tick(&mut bus);
+        }
+        assert_eq!(pixel(&bus, SCREEN_X + 8, SCREEN_Y + 8), (0x00, 0x00, 0x00));
+    }
+
+    // The BG map shows tiles 0-255 so a transfer is VRAM 0x8000-0x8FFF
+    fn show_transfer_tiles(bus: &mut Bus) {
+        for tile in 0..256u16 {
+            bus.write(0x9800 + (tile / 20) * 32 + tile % 20, tile as u8);
+        }
+    }
+
+    fn run_frame(bus: &mut Bus) {
+        for _ in 0..154 * 114 {
+            Ppu::tick(bus);
+        }
+    }
+
+    #[test]
+    fn border_transfer() {
+        let mut bus = sgb_bus();
+        show_transfer_tiles(&mut bus);
+        // map entry 0 is tile 1 with palette 5
+        bus.write(0x8000, 0x01);
+        bus.write(0x8001, 0x04);
+        // palette 5 color 1 blue, the unused space before the palettes green
+        bus.write(0x8000 + 0x800 + 32 + 2, 0x00);
+        bus.write(0x8000 + 0x800 + 32 + 3, 0x7C);
+        bus.write(0x8000 + 0x700 + 32 + 2, 0xE0);
+        bus.write(0x8000 + 0x700 + 32 + 3, 0x03);
+        send_packet(&mut bus, &[COMMAND_PCT_TRN << 3 | 1]);
+        for _ in 0..154 * 114 {
+            Ppu::tick(&mut bus);
+        }
+        assert_eq!(bus.sgb.border_palettes[1][1], 0x7C00);
+
+        // top left row of tile 1 is color 1
+        bus.sgb.border_tiles[BORDER_TILE_SIZE] = 0xFF;
+        bus.sgb.render(&[0; SCREEN_WIDTH * SCREEN_HEIGHT]);
+        assert_eq!(pixel(&bus, 0, 0), (0x00, 0x00, 0xFF));
+        // the other rows are transparent and show the backdrop
+        let backdrop = rgb555_to_rgb888(bus.sgb.palettes[0][0]);
+        assert_eq!(pixel(&bus, 0, 1), backdrop);
+    }
+
+    #[test]
+    fn system_palettes_and_attribute_files() {
+        let mut bus = sgb_bus();
+        show_transfer_tiles(&mut bus);
+        // system palette 3 has red as color 0 and blue as color 1
+        bus.write(0x8000 + 3 * 8, 0x1F);
+        bus.write(0x8000 + 3 * 8 + 3, 0x7C);
+        // attribute file 1 gives the top left tile palette 1
+        bus.write(0x8000 + ATTRIBUTE_FILE_SIZE as u16, 0b0100_0000);
+        send_packet(&mut bus, &[COMMAND_PAL_TRN << 3 | 1]);
+        run_frame(&mut bus);
+        send_packet(&mut bus, &[COMMAND_ATTR_TRN << 3 | 1]);
+        run_frame(&mut bus);
+
+        // palettes 0 and 1 are system palette 3, ATTR_SET applies file 1
+        send_packet(&mut bus, &[COMMAND_PAL_SET << 3 | 1, 3, 0, 3, 0]);
+        send_packet(&mut bus, &[COMMAND_ATTR_SET << 3 | 1, 1]);
+        assert_eq!(bus.sgb.palettes[1], [0x001F, 0x7C00, 0, 0]);
+        assert_eq!(bus.sgb.attributes[0], 1);
+        assert_eq!(bus.sgb.attributes[1], 0);
+
+        // shade 1 on the top left tile is blue, color 0 elsewhere is red
+        let mut shades = [0; SCREEN_WIDTH * SCREEN_HEIGHT];
+        shades[0] = 1;
+        bus.sgb.render(&shades);
+        assert_eq!(pixel(&bus, SCREEN_X, SCREEN_Y), (0x00, 0x00, 0xFF));
+        assert_eq!(pixel(&bus, SCREEN_X + 8, SCREEN_Y), (0xFF, 0x00, 0x00));
+
+        // unknown commands are ignored
+        send_packet(&mut bus, &[0x08 << 3 | 1]);
+        assert_eq!(bus.sgb.attributes[0], 1);
+
+        // version 1 states don't have the transferred palettes and files
+        let mut writer = StateWriter::new();
+        bus.sgb.save_state(&mut writer);
+        let mut data = writer.into_bytes();
+        data.truncate(
+            data.len() - SYSTEM_PALETTE_COUNT * 8 - 4 - ATTRIBUTE_FILE_COUNT * ATTRIBUTE_FILE_SIZE,
+        );
+        data[4..8].copy_from_slice(&1u32.to_le_bytes());
+        let mut state = StateReader::new(&data).unwrap();
+        bus.sgb.load_state(&mut state).unwrap();
+        assert!(state.is_at_end());
+        assert_eq!(bus.sgb.system_palettes[3], [0; 4]);
+    }
+}
@@ -1,3 +1,5 @@
+use crate::util::state::{Snapshot, StateReader, StateWriter};
+
 #[derive(Debug)]
 pub struct Timer {
     divider: u16, // DIV: divider register
@@ -51,6 +53,17 @@ impl Timer {
         (self.divider >> 8) as u8
     }
 
+    /// DIV bit 4 clocks the APU frame sequencer on its falling edge,
+    /// bit 5 in double speed mode where DIV runs twice as fast
+    pub fn apu_clock_bit(&self, is_double_speed: bool) -> bool {
+        let bit = if is_double_speed { 13 } else { 12 };
+        self.divider & (1 << bit) != 0
+    }
+
+    pub fn set_divider(&mut self, divider: u16) {
+        self.divider = divider;
+    }
+
     pub fn reset_divider(&mut self) {
         self.divider = 0;
     }
@@ -89,3 +102,24 @@ impl Timer {
         };
     }
 }
+
+impl Snapshot for Timer {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u16(self.divider);
+        state.u8(self.counter);
+        state.u8(self.modulo);
+        state.u8(self.control);
+        state.bool(self.is_enabled);
+        state.u16(self.mask);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.divider = state.u16()?;
+        self.counter = state.u8()?;
+        self.modulo = state.u8()?;
+        self.control = state.u8()?;
+        self.is_enabled = state.bool()?;
+        self.mask = state.u16()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,111 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// The Game Boy revisions, they differ in the state the boot ROM leaves behind
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Model {
+    Dmg0, // early Japanese DMG
+    #[default]
+    Dmg,
+    Mgb, // Game Boy Pocket
+    Sgb,
+    Sgb2,
+    Cgb,
+    Agb, // Game Boy Advance
+}
+
+const MODELS: [(Model, &str); 7] = [
+    (Model::Dmg0, "dmg0"),
+    (Model::Dmg, "dmg"),
+    (Model::Mgb, "mgb"),
+    (Model::Sgb, "sgb"),
+    (Model::Sgb2, "sgb2"),
+    (Model::Cgb, "cgb"),
+    (Model::Agb, "agb"),
+];
+
+impl Model {
+    /// A, F, B, C, D, E, H, L at 0x0100.
+    /// The DMG and MGB boot ROMs leave H and C set unless the header checksum is 0.
+    pub fn registers(self, header_checksum: u8, is_cgb_mode: bool) -> [u8; 8] {
+        let flags = if header_checksum == 0 { 0x80 } else { 0xB0 };
+        match self {
+            Model::Cgb if is_cgb_mode => [0x11, 0x80, 0x00, 0x00, 0xFF, 0x56, 0x00, 0x0D],
+            Model::Agb if is_cgb_mode => [0x11, 0x00, 0x01, 0x00, 0xFF, 0x56, 0x00, 0x0D],
+            Model::Dmg0 => [0x01, 0x00, 0xFF, 0x13, 0x00, 0xC1, 0x84, 0x03],
+            Model::Dmg => [0x01, flags, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D],
+            Model::Mgb => [0xFF, flags, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D],
+            Model::Sgb => [0x01, 0x00, 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60],
+            Model::Sgb2 => [0xFF, 0x00, 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60],
+            // running DMG cartridges, A = 0x11 is how games detect a CGB
+            Model::Cgb => [0x11, 0x80, 0x00, 0x00, 0x00, 0x08, 0x00, 0x7C],
+            Model::Agb => [0x11, 0x00, 0x01, 0x00, 0x00, 0x08, 0x00, 0x7C],
+        }
+    }
+
+    /// The internal 16-bit divider at 0x0100, DIV is the upper byte
+    pub fn divider(self) -> u16 {
+        match self {
+            Model::Dmg0 => 0x182C,
+            Model::Dmg | Model::Mgb => 0xABCC,
+            // depends on the SNES communication, this is a typical value
+            Model::Sgb | Model::Sgb2 => 0xD85C,
+            Model::Cgb | Model::Agb => 0x267C,
+        }
+    }
+
+    pub fn is_sgb(self) -> bool {
+        matches!(self, Model::Sgb | Model::Sgb2)
+    }
+
+    pub fn is_cgb(self) -> bool {
+        matches!(self, Model::Cgb | Model::Agb)
+    }
+}
+
+impl FromStr for Model {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Model, String> {
+        let name = name.to_lowercase();
+        MODELS
+            .iter()
+            .find(|(_, model_name)| *model_name == name)
+            .map(|(model, _)| *model)
+            .ok_or_else(|| {
+                let names: Vec<&str> = MODELS.iter().map(|(_, name)| *name).collect();
+                format!(
+                    "Unknown model {}, expected one of {}",
+                    name,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+impl fmt::Display for Model {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (_, name) = MODELS.iter().find(|(model, _)| model == self).unwrap();
+        write!(f, "{}", name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_names() {
+        assert_eq!("SGB2".parse::<Model>(), Ok(Model::Sgb2));
+        assert_eq!("dmg0".parse::<Model>(), Ok(Model::Dmg0));
+        assert!("gba".parse::<Model>().is_err());
+        assert_eq!(Model::Mgb.to_string(), "mgb");
+    }
+
+    #[test]
+    fn header_checksum_flags() {
+        assert_eq!(Model::Dmg.registers(0x00, false)[1], 0x80);
+        assert_eq!(Model::Dmg.registers(0x33, false)[1], 0xB0);
+        assert_eq!(Model::Sgb.registers(0x33, false)[1], 0x00);
+    }
+}
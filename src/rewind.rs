@@ -0,0 +1,294 @@
+use crate::Emulator;
+use std::collections::VecDeque;
+
+// Every this many snapshots a full keyframe is stored, the others are deltas against it
+const KEYFRAME_INTERVAL: usize = 60;
+
+/// A snapshot compressed as the XOR against its keyframe, with runs of
+/// unchanged bytes encoded as counts
+struct Entry {
+    data: Vec<u8>,
+    is_keyframe: bool,
+}
+
+/// Snapshots of the machine taken every few frames, popped newest first to rewind.
+/// At least `capacity` snapshots are kept, the oldest are dropped once there
+/// are more or they take up more than `max_memory` bytes.
+pub struct Rewind {
+    interval: u32, // frames between snapshots
+    frames: u32,   // frames since the last snapshot
+    max_entries: usize,
+    max_memory: usize,
+    memory: usize, // bytes used by the snapshots and the keyframe
+    entries: VecDeque<Entry>,
+    keyframe: Option<Vec<u8>>, // the uncompressed keyframe new deltas are against
+    deltas: usize,             // deltas stored since the keyframe
+}
+
+fn write_count(output: &mut Vec<u8>, mut count: usize) {
+    // 7 bits per byte, the high bit marks that more bytes follow
+    while count >= 0x80 {
+        output.push(count as u8 | 0x80);
+        count >>= 7;
+    }
+    output.push(count as u8);
+}
+
+fn read_count(input: &[u8], position: &mut usize) -> usize {
+    let mut count = 0;
+    let mut shift = 0;
+    while let Some(byte) = input.get(*position) {
+        *position += 1;
+        count |= ((byte & 0x7F) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    count
+}
+
+// The length of `state`, then pairs of an unchanged run and changed bytes
+fn encode(state: &[u8], base: &[u8]) -> Vec<u8> {
+    let xor = |index: usize| state[index] ^ base.get(index).copied().unwrap_or(0);
+    let mut output = Vec::new();
+    write_count(&mut output, state.len());
+    let mut index = 0;
+    while index < state.len() {
+        let unchanged_start = index;
+        while index < state.len() && xor(index) == 0 {
+            index += 1;
+        }
+        let changed_start = index;
+        while index < state.len() && xor(index) != 0 {
+            index += 1;
+        }
+        write_count(&mut output, changed_start - unchanged_start);
+        write_count(&mut output, index - changed_start);
+        output.extend((changed_start..index).map(xor));
+    }
+    output
+}
+
+fn decode(data: &[u8], base: &[u8]) -> Vec<u8> {
+    let mut position = 0;
+    let length = read_count(data, &mut position);
+    let mut state: Vec<u8> = (0..length)
+        .map(|index| base.get(index).copied().unwrap_or(0))
+        .collect();
+    let mut index = 0;
+    while position < data.len() {
+        index += read_count(data, &mut position);
+        let changed = read_count(data, &mut position);
+        for byte in &data[position..position + changed] {
+            state[index] ^= byte;
+            index += 1;
+        }
+        position += changed;
+    }
+    state
+}
+
+impl Rewind {
+    pub fn new(interval: u32, capacity: usize, max_memory: usize) -> Self {
+        Rewind {
+            interval: interval.max(1),
+            frames: 0,
+            // a keyframe is dropped with all of its deltas, the extra
+            // group keeps `capacity` snapshots after that
+            max_entries: capacity + KEYFRAME_INTERVAL + 1,
+            max_memory,
+            memory: 0,
+            entries: VecDeque::new(),
+            keyframe: None,
+            deltas: 0,
+        }
+    }
+
+    /// Called after every emulated frame, takes a snapshot every `interval` frames
+    pub fn push_frame(&mut self, emulator: &Emulator) {
+        self.frames += 1;
+        if self.frames >= self.interval {
+            self.frames = 0;
+            self.push(emulator.save_state());
+        }
+    }
+
+    pub fn push(&mut self, state: Vec<u8>) {
+        let entry = match &self.keyframe {
+            Some(keyframe) if self.deltas < KEYFRAME_INTERVAL => {
+                self.deltas += 1;
+                Entry {
+                    data: encode(&state, keyframe),
+                    is_keyframe: false,
+                }
+            }
+            _ => {
+                let entry = Entry {
+                    data: encode(&state, &[]),
+                    is_keyframe: true,
+                };
+                self.set_keyframe(Some(state));
+                self.deltas = 0;
+                entry
+            }
+        };
+        self.memory += entry.data.len();
+        self.entries.push_back(entry);
+
+        while self.entries.len() > self.max_entries || self.memory > self.max_memory {
+            self.drop_oldest();
+        }
+    }
+
+    // Deltas can't be decoded without their keyframe, so the keyframe
+    // is dropped together with its deltas
+    fn drop_oldest(&mut self) {
+        while let Some(entry) = self.entries.pop_front() {
+            self.memory -= entry.data.len();
+            if self.entries.front().is_none_or(|entry| entry.is_keyframe) {
+                break;
+            }
+        }
+        if self.entries.is_empty() {
+            self.set_keyframe(None);
+        }
+    }
+
+    fn set_keyframe(&mut self, keyframe: Option<Vec<u8>>) {
+        let length = |keyframe: &Option<Vec<u8>>| keyframe.as_ref().map_or(0, Vec::len);
+        self.memory = self.memory - length(&self.keyframe) + length(&keyframe);
+        self.keyframe = keyframe;
+    }
+
+    /// Removes and returns the most recent snapshot
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let entry = self.entries.pop_back()?;
+        self.memory -= entry.data.len();
+        self.frames = 0;
+        // new snapshots start with a keyframe after rewinding
+        self.set_keyframe(None);
+        if entry.is_keyframe {
+            return Some(decode(&entry.data, &[]));
+        }
+        let keyframe = self.entries.iter().rev().find(|entry| entry.is_keyframe)?;
+        Some(decode(&entry.data, &decode(&keyframe.data, &[])))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Bytes taken by the compressed snapshots and the keyframe they are against
+    pub fn memory(&self) -> usize {
+        self.memory
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(frame: u8) -> Vec<u8> {
+        let mut state = vec![0x55; 1000];
+        state[10] = frame;
+        state[500..510].fill(frame);
+        state
+    }
+
+    #[test]
+    fn delta_encoding() {
+        let base = state(1);
+        let changed = state(2);
+        let encoded = encode(&changed, &base);
+        assert!(encoded.len() < 40);
+        assert_eq!(decode(&encoded, &base), changed);
+        // states of another length
+        assert_eq!(
+            decode(&encode(&changed[..600], &base), &base),
+            changed[..600]
+        );
+        assert_eq!(
+            decode(&encode(&base, &changed[..600]), &changed[..600]),
+            base
+        );
+    }
+
+    #[test]
+    fn pops_newest_first_across_keyframes() {
+        let mut rewind = Rewind::new(1, 1000, usize::MAX);
+        for frame in 0..150 {
+            rewind.push(state(frame));
+        }
+        for frame in (0..150).rev() {
+            assert_eq!(rewind.pop(), Some(state(frame)));
+        }
+        assert!(rewind.pop().is_none());
+        assert_eq!(rewind.memory(), 0);
+    }
+
+    #[test]
+    fn drops_oldest_keyframe_with_its_deltas() {
+        let mut rewind = Rewind::new(1, 40, usize::MAX);
+        for frame in 0..=(KEYFRAME_INTERVAL as u8 + 41) {
+            rewind.push(state(frame));
+        }
+        // the first keyframe and its deltas are gone
+        assert_eq!(rewind.len(), 41);
+        assert_eq!(
+            rewind
+                .entries
+                .iter()
+                .filter(|entry| entry.is_keyframe)
+                .count(),
+            1
+        );
+
+        let mut rewind = Rewind::new(1, 1000, 50_000);
+        for frame in 0..255 {
+            rewind.push(state(frame));
+            assert!(rewind.memory() <= 50_000);
+        }
+        assert_eq!(rewind.pop(), Some(state(254)));
+    }
+
+    #[test]
+    fn keeps_capacity_after_wrapping() {
+        let mut rewind = Rewind::new(1, 100, usize::MAX);
+        for frame in 0..255 {
+            rewind.push(state(frame));
+            assert!(rewind.len() >= (frame as usize + 1).min(100));
+        }
+        for frame in (255 - 100..255).rev() {
+            assert_eq!(rewind.pop(), Some(state(frame)));
+        }
+    }
+
+    #[test]
+    fn keyframe_counts_as_memory() {
+        let mut rewind = Rewind::new(1, 100, usize::MAX);
+        rewind.push(state(1));
+        let compressed = encode(&state(1), &[]).len();
+        assert_eq!(rewind.memory(), compressed + state(1).len());
+        rewind.push(state(2));
+        rewind.pop();
+        assert_eq!(rewind.memory(), compressed);
+    }
+
+    #[test]
+    fn snapshot_every_interval() {
+        let mut emulator = Emulator::from_bytes(vec![0; 0x8000]).unwrap();
+        let mut rewind = Rewind::new(3, 100, usize::MAX);
+        for _ in 0..7 {
+            emulator.step_frame();
+            rewind.push_frame(&emulator);
+        }
+        assert_eq!(rewind.len(), 2);
+        let state = rewind.pop().unwrap();
+        emulator.load_state(&state).unwrap();
+    }
+}
@@ -0,0 +1,132 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// How fast the emulation runs compared to the real hardware
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Speed {
+    Quarter,
+    Half,
+    #[default]
+    Normal,
+    Double,
+    Quadruple,
+    Uncapped, // as fast as possible without audio
+}
+
+// From slowest to fastest, with the names used on the command line
+const SPEEDS: [(Speed, &str); 6] = [
+    (Speed::Quarter, "0.25"),
+    (Speed::Half, "0.5"),
+    (Speed::Normal, "1"),
+    (Speed::Double, "2"),
+    (Speed::Quadruple, "4"),
+    (Speed::Uncapped, "uncapped"),
+];
+
+impl Speed {
+    pub fn multiplier(self) -> f64 {
+        match self {
+            Speed::Quarter => 0.25,
+            Speed::Half => 0.5,
+            Speed::Normal => 1.0,
+            Speed::Double => 2.0,
+            Speed::Quadruple => 4.0,
+            Speed::Uncapped => f64::INFINITY,
+        }
+    }
+
+    // Emulated frames per presented frame, the others are not rendered.
+    // None runs frames until the time of one displayed frame has passed.
+    pub fn frames_per_present(self) -> Option<u32> {
+        match self {
+            Speed::Quarter | Speed::Half | Speed::Normal => Some(1),
+            Speed::Double => Some(2),
+            Speed::Quadruple => Some(4),
+            Speed::Uncapped => None,
+        }
+    }
+
+    fn index(self) -> usize {
+        SPEEDS.iter().position(|(speed, _)| *speed == self).unwrap()
+    }
+
+    // Stepping stops at 4x, uncapped is only reached with the toggle
+    pub fn faster(self) -> Speed {
+        SPEEDS[(self.index() + 1).min(Speed::Quadruple.index())].0
+    }
+
+    pub fn slower(self) -> Speed {
+        SPEEDS[self.index().saturating_sub(1)].0
+    }
+
+    pub fn toggle_uncapped(self) -> Speed {
+        if self == Speed::Uncapped {
+            Speed::Normal
+        } else {
+            Speed::Uncapped
+        }
+    }
+}
+
+impl FromStr for Speed {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Speed, String> {
+        let name = name.to_lowercase();
+        SPEEDS
+            .iter()
+            .find(|(_, speed_name)| *speed_name == name)
+            .map(|(speed, _)| *speed)
+            .ok_or_else(|| {
+                let names: Vec<&str> = SPEEDS.iter().map(|(_, name)| *name).collect();
+                format!(
+                    "Unknown speed {}, expected one of {}",
+                    name,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+impl fmt::Display for Speed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", SPEEDS[self.index()].1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_display() {
+        assert_eq!("0.25".parse(), Ok(Speed::Quarter));
+        assert_eq!("Uncapped".parse(), Ok(Speed::Uncapped));
+        assert!("3".parse::<Speed>().is_err());
+        assert!("".parse::<Speed>().is_err());
+        for (speed, name) in SPEEDS {
+            assert_eq!(speed.to_string(), name);
+        }
+    }
+
+    #[test]
+    fn stepping_and_toggling() {
+        assert_eq!(Speed::Quarter.slower(), Speed::Quarter);
+        assert_eq!(Speed::Quarter.faster(), Speed::Half);
+        assert_eq!(Speed::Double.slower(), Speed::Normal);
+        assert_eq!(Speed::Quadruple.faster(), Speed::Quadruple);
+        // uncapped is only reached with the toggle
+        assert_eq!(Speed::Uncapped.slower(), Speed::Quadruple);
+        assert_eq!(Speed::Half.toggle_uncapped(), Speed::Uncapped);
+        assert_eq!(Speed::Uncapped.toggle_uncapped(), Speed::Normal);
+    }
+
+    #[test]
+    fn skipped_frames() {
+        assert_eq!(Speed::Quarter.frames_per_present(), Some(1));
+        assert_eq!(Speed::Normal.frames_per_present(), Some(1));
+        assert_eq!(Speed::Quadruple.frames_per_present(), Some(4));
+        assert_eq!(Speed::Uncapped.frames_per_present(), None);
+        assert_eq!(Speed::Half.multiplier(), 0.5);
+    }
+}
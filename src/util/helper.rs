@@ -19,18 +19,23 @@ pub fn is_bit_set(data: u8, index: u8) -> bool {
 }
 
 pub fn set_bit(data: u8, index: u8, value: bool) -> u8 {
-    (if value { 1 } else { 0 } << index) | data
+    if value {
+        data | (1 << index)
+    } else {
+        data & !(1 << index)
+    }
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
     fn test_is_bit_set() {
         let data = 0b100;
-        assert_eq!(is_bit_set(data, 0), false);
-        assert_eq!(is_bit_set(data, 1), false);
-        assert_eq!(is_bit_set(data, 2), true);
+        assert!(!is_bit_set(data, 0));
+        assert!(!is_bit_set(data, 1));
+        assert!(is_bit_set(data, 2));
     }
 
     #[test]
@@ -39,5 +44,6 @@ mod tests {
         assert_eq!(0b0010_0001, set_bit(0b0010_0001, 0, true));
         assert_eq!(0b0010_0001, set_bit(0b0010_0000, 0, true));
         assert_eq!(0b1111_0111, set_bit(0b1111_0111, 3, false));
+        assert_eq!(0b1111_0111, set_bit(0b1111_1111, 3, false));
     }
 }
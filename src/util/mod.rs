@@ -1 +1,9 @@
 pub mod helper;
+pub mod png;
+// only the SDL frontend plays audio
+#[cfg(any(feature = "sdl", test))]
+pub mod resampler;
+pub mod state;
+// and records it
+#[cfg(any(feature = "sdl", test))]
+pub mod wav;
@@ -0,0 +1,53 @@
+/// Converts interleaved stereo samples between sample rates by linear interpolation
+#[derive(Debug)]
+pub struct Resampler {
+    step: f64,     // input samples per output sample
+    position: f64, // fractional position between `previous` and the next input sample
+    previous: [f32; 2],
+}
+
+impl Resampler {
+    pub fn new(input_rate: u32, output_rate: u32) -> Self {
+        Resampler {
+            step: input_rate as f64 / output_rate as f64,
+            position: 0.0,
+            previous: [0.0; 2],
+        }
+    }
+
+    pub fn resample(&mut self, input: &[f32]) -> Vec<f32> {
+        let mut output = Vec::with_capacity((input.len() as f64 / self.step) as usize + 2);
+        for frame in input.chunks_exact(2) {
+            while self.position < 1.0 {
+                for (from, to) in self.previous.iter().zip(frame) {
+                    output.push(from + (to - from) * self.position as f32);
+                }
+                self.position += self.step;
+            }
+            self.position -= 1.0;
+            self.previous = [frame[0], frame[1]];
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn output_length_follows_rate() {
+        let mut resampler = Resampler::new(65536, 48000);
+        let input = vec![0.5; 2 * 65536];
+        let output = resampler.resample(&input);
+        assert!(output.len().abs_diff(2 * 48000) <= 2);
+        assert!(output[2..].iter().all(|sample| *sample == 0.5));
+    }
+
+    #[test]
+    fn interpolates_between_samples() {
+        let mut resampler = Resampler::new(1, 2);
+        let output = resampler.resample(&[1.0, -1.0, 1.0, -1.0]);
+        assert_eq!(output, vec![0.0, 0.0, 0.5, -0.5, 1.0, -1.0, 1.0, -1.0]);
+    }
+}
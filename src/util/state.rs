@@ -0,0 +1,225 @@
+/// Save states are a little endian byte stream, each component writes
+/// its fields in a fixed order and reads them back in the same order.
+/// Bump the version whenever that order or a field changes. Older states
+/// stay loadable, components check `StateReader::version` before reading
+/// fields added later and use defaults for them otherwise.
+pub const STATE_VERSION: u32 = 2;
+const MAGIC: &[u8; 4] = b"GBST";
+
+/// Implemented by every component that is part of the machine state
+pub trait Snapshot {
+    fn save_state(&self, state: &mut StateWriter);
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String>;
+}
+
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        let mut data = Vec::new();
+        data.extend_from_slice(MAGIC);
+        data.extend_from_slice(&STATE_VERSION.to_le_bytes());
+        StateWriter { data }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn i32(&mut self, value: i32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn f32(&mut self, value: f32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Length prefixed bytes
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len() as u32);
+        self.data.extend_from_slice(bytes);
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+    version: u32,
+}
+
+impl<'a> StateReader<'a> {
+    /// Checks the header, states of newer versions can't be read
+    pub fn new(data: &'a [u8]) -> Result<Self, String> {
+        if data.len() < 8 || &data[..4] != MAGIC {
+            return Err("Not a save state".to_string());
+        }
+        let version = u32::from_le_bytes([data[4], data[5], data[6], data[7]]);
+        if version > STATE_VERSION {
+            return Err(format!(
+                "The save state has version {}, this build only supports up to version {}",
+                version, STATE_VERSION
+            ));
+        }
+        Ok(StateReader {
+            data,
+            position: 8,
+            version,
+        })
+    }
+
+    /// The version the state was written with
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], String> {
+        let end = self
+            .position
+            .checked_add(length)
+            .filter(|end| *end <= self.data.len())
+            .ok_or("The save state is truncated")?;
+        let bytes = &self.data[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    pub fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, String> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    pub fn i32(&mut self) -> Result<i32, String> {
+        Ok(i32::from_le_bytes(self.array()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    pub fn f32(&mut self) -> Result<f32, String> {
+        Ok(f32::from_le_bytes(self.array()?))
+    }
+
+    pub fn bytes(&mut self) -> Result<Vec<u8>, String> {
+        let length = self.u32()? as usize;
+        Ok(self.take(length)?.to_vec())
+    }
+
+    /// Reads bytes into a buffer of a fixed size like RAM
+    pub fn bytes_into(&mut self, buffer: &mut [u8]) -> Result<(), String> {
+        let length = self.u32()? as usize;
+        if length != buffer.len() {
+            return Err(format!(
+                "The save state has {} bytes where {} are expected",
+                length,
+                buffer.len()
+            ));
+        }
+        buffer.copy_from_slice(self.take(length)?);
+        Ok(())
+    }
+
+    /// Whether all of the state was read
+    pub fn is_at_end(&self) -> bool {
+        self.position == self.data.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut writer = StateWriter::new();
+        writer.u8(1);
+        writer.u16(0x1234);
+        writer.i32(-5);
+        writer.bytes(&[1, 2, 3]);
+        let data = writer.into_bytes();
+
+        let mut reader = StateReader::new(&data).unwrap();
+        assert_eq!(reader.u8(), Ok(1));
+        assert_eq!(reader.u16(), Ok(0x1234));
+        assert_eq!(reader.i32(), Ok(-5));
+        let mut buffer = [0; 3];
+        reader.bytes_into(&mut buffer).unwrap();
+        assert_eq!(buffer, [1, 2, 3]);
+        assert!(reader.is_at_end());
+        assert!(reader.u8().is_err());
+
+        // buffers of another size are rejected
+        let mut reader = StateReader::new(&data).unwrap();
+        reader.u8().unwrap();
+        reader.u16().unwrap();
+        reader.i32().unwrap();
+        assert!(reader.bytes_into(&mut [0; 2]).is_err());
+    }
+
+    #[test]
+    fn newer_version_is_rejected() {
+        let mut data = StateWriter::new().into_bytes();
+        data[4..8].copy_from_slice(&(STATE_VERSION + 1).to_le_bytes());
+        assert!(StateReader::new(&data).is_err());
+        assert!(StateReader::new(b"GBS").is_err());
+    }
+
+    #[test]
+    fn older_version_is_loaded() {
+        // the current version wrote a field the older one doesn't have
+        let mut writer = StateWriter::new();
+        writer.u16(0x1234);
+        writer.u8(7);
+        let mut data = writer.into_bytes();
+        data.pop();
+        data[4..8].copy_from_slice(&(STATE_VERSION - 1).to_le_bytes());
+
+        let mut reader = StateReader::new(&data).unwrap();
+        assert_eq!(reader.version(), STATE_VERSION - 1);
+        assert_eq!(reader.u16(), Ok(0x1234));
+        let added = if reader.version() >= STATE_VERSION {
+            reader.u8().unwrap()
+        } else {
+            0
+        };
+        assert_eq!(added, 0);
+        assert!(reader.is_at_end());
+    }
+}
@@ -0,0 +1,115 @@
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const HEADER_SIZE: u32 = 44;
+// The RIFF size in the header is 32 bits
+const MAX_DATA_SIZE: u32 = u32::MAX - (HEADER_SIZE - 8);
+
+/// Writes 16-bit PCM WAV files, the sizes in the header are filled in on `finish`
+pub struct WavWriter {
+    writer: BufWriter<File>,
+    data_size: u32, // bytes of sample data written
+}
+
+impl WavWriter {
+    pub fn create(path: &Path, channels: u16, sample_rate: u32) -> Result<WavWriter, String> {
+        let file = File::create(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mut writer = BufWriter::new(file);
+        let block_align = channels * 2;
+
+        let mut header = Vec::with_capacity(HEADER_SIZE as usize);
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&0u32.to_le_bytes()); // RIFF size, set on finish
+        header.extend_from_slice(b"WAVEfmt ");
+        header.extend_from_slice(&16u32.to_le_bytes());
+        header.extend_from_slice(&1u16.to_le_bytes()); // PCM
+        header.extend_from_slice(&channels.to_le_bytes());
+        header.extend_from_slice(&sample_rate.to_le_bytes());
+        header.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+        header.extend_from_slice(&block_align.to_le_bytes());
+        header.extend_from_slice(&16u16.to_le_bytes()); // bits per sample
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&0u32.to_le_bytes()); // data size, set on finish
+        writer.write_all(&header).map_err(|e| e.to_string())?;
+
+        Ok(WavWriter {
+            writer,
+            data_size: 0,
+        })
+    }
+
+    /// `samples` are interleaved if there is more than one channel
+    pub fn write_samples(&mut self, samples: &[f32]) -> Result<(), String> {
+        let data_size = u32::try_from(samples.len() * 2)
+            .ok()
+            .and_then(|size| self.data_size.checked_add(size))
+            .filter(|size| *size <= MAX_DATA_SIZE)
+            .ok_or("The WAV file reached its 4 GiB limit")?;
+        for sample in samples {
+            let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            self.writer
+                .write_all(&sample.to_le_bytes())
+                .map_err(|e| e.to_string())?;
+        }
+        self.data_size = data_size;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<(), String> {
+        let mut patch = |position: u64, value: u32| -> std::io::Result<()> {
+            self.writer.seek(SeekFrom::Start(position))?;
+            self.writer.write_all(&value.to_le_bytes())
+        };
+        patch(4, HEADER_SIZE - 8 + self.data_size).map_err(|e| e.to_string())?;
+        patch(40, self.data_size).map_err(|e| e.to_string())?;
+        self.writer.flush().map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_and_samples() {
+        let path = std::env::temp_dir().join(format!("gb-wav-test-{}.wav", std::process::id()));
+        let mut wav = WavWriter::create(&path, 2, 65536).unwrap();
+        wav.write_samples(&[0.0, 1.0, -1.0, 2.0]).unwrap();
+        wav.finish().unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(bytes.len(), 44 + 8);
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(bytes[4..8].try_into().unwrap()), 36 + 8);
+        assert_eq!(u16::from_le_bytes([bytes[22], bytes[23]]), 2);
+        assert_eq!(u32::from_le_bytes(bytes[24..28].try_into().unwrap()), 65536);
+        assert_eq!(u32::from_le_bytes(bytes[40..44].try_into().unwrap()), 8);
+        assert_eq!(i16::from_le_bytes([bytes[46], bytes[47]]), i16::MAX);
+        assert_eq!(i16::from_le_bytes([bytes[48], bytes[49]]), -i16::MAX);
+        assert_eq!(i16::from_le_bytes([bytes[50], bytes[51]]), i16::MAX);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn size_limit() {
+        let path = std::env::temp_dir().join(format!("gb-wav-limit-{}.wav", std::process::id()));
+        let mut wav = WavWriter::create(&path, 1, 65536).unwrap();
+        wav.data_size = MAX_DATA_SIZE - 4;
+        wav.write_samples(&[0.0, 0.0]).unwrap();
+        assert!(wav.write_samples(&[0.0]).is_err());
+        assert_eq!(wav.data_size, MAX_DATA_SIZE);
+        wav.finish().unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(
+            u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+            u32::MAX
+        );
+        assert_eq!(
+            u32::from_le_bytes(bytes[40..44].try_into().unwrap()),
+            MAX_DATA_SIZE
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}